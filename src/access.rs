@@ -0,0 +1,226 @@
+// Hides paths matched by [access] deny globs or by a gitignore-style .hunkignore
+// at the root, unless they're matched by an [access] allow glob.
+//
+// Checked in path::get_entity_path so that Root, Browse, try_files, and the file
+// cache all agree on what's hidden. Hidden paths are served as a 404.
+
+use std::fmt;
+use std::path::Path;
+
+use config;
+use glob::Glob;
+use watched::Watched;
+
+pub const IGNORE_FILE: &str = ".hunkignore";
+
+#[derive(Debug, Clone)]
+pub struct IgnoreRule {
+    glob: Glob,
+    // "!pattern" re-includes what an earlier line excluded
+    negate: bool,
+    // "pattern/" only matches directories
+    dir_only: bool,
+}
+
+// <https://git-scm.com/docs/gitignore#_pattern_format>
+pub fn parse_ignore(text: &str) -> Vec<IgnoreRule> {
+    let mut rules = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let mut line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let negate = line.starts_with('!');
+        if negate {
+            line = &line[1..];
+        }
+
+        // \# and \! for patterns that really start with those
+        if line.starts_with("\\#") || line.starts_with("\\!") {
+            line = &line[1..];
+        }
+
+        let dir_only = line.ends_with('/');
+        let line = line.trim_end_matches('/');
+
+        match Glob::new(line) {
+            Ok(glob) =>
+                rules.push(IgnoreRule { glob, negate, dir_only }),
+            Err(e) =>
+                warn!("{} line {}: {}", IGNORE_FILE, i + 1, e),
+        }
+    }
+    rules
+}
+
+// The last matching rule wins, like git.
+fn is_ignored(rules: &[IgnoreRule], path: &str, is_dir: bool) -> bool {
+    rules.iter()
+        .filter(|rule| is_dir || !rule.dir_only)
+        .rfind(|rule| rule.glob.is_match(path))
+        .map(|rule| !rule.negate)
+        .unwrap_or(false)
+}
+
+#[derive(Default)]
+pub struct Access {
+    deny: Vec<Glob>,
+    allow: Vec<Glob>,
+    ignore: Option<Watched<Vec<IgnoreRule>>>,
+}
+
+impl fmt::Debug for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Access")
+            .field("deny", &self.deny)
+            .field("allow", &self.allow)
+            .field("ignore", &self.ignore.as_ref().map(|ignore| ignore.path()))
+            .finish()
+    }
+}
+
+impl Access {
+    pub fn new(config: &Option<config::Access>, root: &Path) -> Self {
+        let (deny, allow) = match *config {
+            Some(ref config) => (config.deny.clone(), config.allow.clone()),
+            None => (Vec::new(), Vec::new()),
+        };
+        Access {
+            deny,
+            allow,
+            ignore: Some(Watched::new(root.join(IGNORE_FILE), parse_ignore)),
+        }
+    }
+
+    // req_path is the decoded request path. Directories end with a slash, e.g. "/docs/".
+    pub fn allows(&self, req_path: &str) -> bool {
+        if req_path.trim_end_matches('/') == format!("/{}", IGNORE_FILE) {
+            return false;
+        }
+
+        // Watched only stats .hunkignore once a second, so with no deny globs and
+        // no .hunkignore this is just an uncontended lock.
+        let ignore = self.ignore.as_ref().map(|ignore| ignore.get());
+        let ignore: &[IgnoreRule] = match ignore {
+            Some(ref rules) => rules,
+            None => &[],
+        };
+
+        if self.deny.is_empty() && ignore.is_empty() {
+            return true;
+        }
+
+        // A hidden directory hides everything inside it, so check each ancestor
+        // on the way down, e.g. /a, /a/b, then /a/b/c.txt.
+        let is_dir = req_path.ends_with('/');
+        let trimmed = req_path.trim_end_matches('/');
+        let mut hidden = false;
+        for (i, _) in trimmed.match_indices('/').skip(1).chain(Some((trimmed.len(), ""))) {
+            let prefix = &trimmed[..i];
+            let prefix_is_dir = i < trimmed.len() || is_dir;
+
+            let denied = self.deny.iter().any(|glob| {
+                glob.is_match(prefix) || (prefix_is_dir && glob.is_match(&format!("{}/", prefix)))
+            });
+
+            if denied || is_ignored(ignore, prefix, prefix_is_dir) {
+                hidden = true;
+                break;
+            }
+        }
+
+        !hidden || self.allow.iter().any(|glob| glob.is_match(req_path))
+    }
+}
+
+#[test]
+fn test_allows() {
+    fn globs(xs: &[&str]) -> Vec<Glob> {
+        xs.iter().map(|x| Glob::new(x).unwrap()).collect()
+    }
+
+    let access = Access {
+        deny: globs(&["**/*.map", "/internal/**"]),
+        allow: globs(&["/internal/public/**"]),
+        ignore: None,
+    };
+
+    // (path, expected)
+    let table = vec![
+        ("/app.js", true),
+        ("/app.js.map", false),
+        ("/js/app.js.map", false),
+        ("/internal/", false),
+        ("/internal/manifest.json", false),
+        ("/internal/public/logo.png", true),
+        ("/internals.txt", true),
+        ("/.hunkignore", false),
+    ];
+
+    for (path, expected) in table {
+        assert_eq!(expected, access.allows(path), "{:?}", path);
+    }
+}
+
+#[test]
+fn test_hunkignore() {
+    use std::fs;
+
+    let root = ::std::env::temp_dir().join(format!("hunk-test-hunkignore-{}", ::std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+
+    // No deny globs and no .hunkignore takes the fast path
+    let missing = Access::new(&None, &root);
+    assert!(missing.allows("/a.log"));
+    assert!(!missing.allows("/.hunkignore"));
+
+    fs::write(root.join(IGNORE_FILE), "*.log\n").unwrap();
+    let present = Access::new(&None, &root);
+    assert!(!present.allows("/a.log"));
+    assert!(present.allows("/a.txt"));
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_is_ignored() {
+    let rules = parse_ignore("
+# comment
+*.log
+!keep.log
+/build/
+secret/
+docs/*.draft.md
+\\#hash
+");
+
+    // Walk the ancestors like Access::allows does
+    let ignored = |path: &str| {
+        let is_dir = path.ends_with('/');
+        let trimmed = path.trim_end_matches('/');
+        trimmed.match_indices('/').skip(1).chain(Some((trimmed.len(), "")))
+            .any(|(i, _)| is_ignored(&rules, &trimmed[..i], i < trimmed.len() || is_dir))
+    };
+
+    // (path, expected)
+    let table = vec![
+        ("/error.log", true),
+        ("/a/b/error.log", true),
+        ("/keep.log", false),
+        ("/build/app.js", true),
+        ("/build/", true),
+        // Only directories named build at the root
+        ("/src/build/app.js", false),
+        // A file named build isn't a directory
+        ("/build", false),
+        ("/a/secret/key.pem", true),
+        ("/docs/post.draft.md", true),
+        ("/docs/post.md", false),
+        ("/#hash", true),
+    ];
+
+    for (path, expected) in table {
+        assert_eq!(expected, ignored(path), "{:?}", path);
+    }
+}
@@ -24,7 +24,8 @@ document.querySelector('#filter').addEventListener('keyup', function(e) {
   var entries = document.querySelectorAll('tbody tr.entry td:first-child')
   for (var i = 0, len = entries.length; i < len; i++) {
     var el = entries[i]
-    var filename = el.textContent.toLowerCase()
+    // Match the name only, not a symlink's target
+    var filename = el.querySelector('.filename').textContent.toLowerCase()
     if (fuzzysearch(query, filename)) {
       el.parentNode.style.display = 'table-row'
     } else {
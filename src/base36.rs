@@ -15,7 +15,7 @@ pub fn encode(mut n: u64) -> String {
         n /= BASE
     }
     buf[i] = ALPHABET[n as usize];
-    buf[(MAX_CAP - cap)..].into_iter().collect()
+    buf[(MAX_CAP - cap)..].iter().collect()
 }
 
 // PRIVATE
@@ -1,28 +1,272 @@
-use std::io::Write;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
 
 use futures_cpupool::CpuPool;
-use futures::{Sink, Stream};
-use flate2::{Compression, write::GzEncoder};
-use hyper::Body;
+use futures::{stream, Async, Poll, Stream};
+use flate2::{Compression, write::{GzEncoder, ZlibEncoder}};
+use brotli;
+use zstd;
+use hyper::{self, header, Body, Chunk};
 
-/// Transforms the body into a new one where all of its chunks will be gzipped.
+use util;
+
+// The content-codings we know how to produce.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoder {
+    #[serde(rename = "br")]
+    Brotli,
+    #[serde(rename = "zstd")]
+    Zstd,
+    #[serde(rename = "gzip")]
+    Gzip,
+    #[serde(rename = "deflate")]
+    Deflate,
+}
+
+impl Encoder {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Encoder::Brotli => "br",
+            Encoder::Zstd => "zstd",
+            Encoder::Gzip => "gzip",
+            Encoder::Deflate => "deflate",
+        }
+    }
+
+    pub fn header(&self) -> header::Encoding {
+        match *self {
+            Encoder::Brotli => header::Encoding::Brotli,
+            Encoder::Zstd => header::Encoding::EncodingExt("zstd".to_string()),
+            Encoder::Gzip => header::Encoding::Gzip,
+            Encoder::Deflate => header::Encoding::Deflate,
+        }
+    }
+
+    // File extension of precompressed sidecars, e.g. app.js.br
+    pub fn extension(&self) -> Option<&'static str> {
+        match *self {
+            Encoder::Brotli => Some("br"),
+            Encoder::Zstd => Some("zst"),
+            Encoder::Gzip => Some("gz"),
+            Encoder::Deflate => None,
+        }
+    }
+
+    // Whether an Accept-Encoding item refers to this encoder.
+    pub fn matches(&self, encoding: &header::Encoding) -> bool {
+        match (*self, encoding) {
+            (Encoder::Brotli, &header::Encoding::Brotli) => true,
+            (Encoder::Gzip, &header::Encoding::Gzip) => true,
+            (Encoder::Deflate, &header::Encoding::Deflate) => true,
+            (_, header::Encoding::EncodingExt(ext)) => ext.eq_ignore_ascii_case(self.name()),
+            _ => false,
+        }
+    }
+
+    // The levels the codec accepts, checked when the config is loaded.
+    pub fn levels(&self) -> RangeInclusive<u32> {
+        match *self {
+            Encoder::Brotli => 0..=11,
+            Encoder::Zstd => 1..=22,
+            Encoder::Gzip => 0..=9,
+            Encoder::Deflate => 0..=9,
+        }
+    }
+
+    // Levels that favor speed since we're compressing on the fly.
+    // e.g. gzip 1 has the maximum compression to cpu ratio.
+    pub fn default_level(&self) -> u32 {
+        match *self {
+            Encoder::Brotli => 4,
+            Encoder::Zstd => 3,
+            Encoder::Gzip => 1,
+            Encoder::Deflate => 1,
+        }
+    }
+
+    fn codec(&self, level: u32) -> io::Result<Box<dyn Codec>> {
+        let buf = Buffer::default();
+        let codec: Box<dyn Codec> = match *self {
+            Encoder::Brotli =>
+                Box::new(Streaming {
+                    // (writer, buffer size, quality 0-11, lgwin)
+                    encoder: brotli::CompressorWriter::new(buf.clone(), 4096, level, 22),
+                    buf,
+                    finish: |encoder| { encoder.into_inner(); Ok(()) },
+                }),
+            Encoder::Zstd =>
+                Box::new(Streaming {
+                    encoder: zstd::stream::write::Encoder::new(buf.clone(), level as i32)?,
+                    buf,
+                    finish: |encoder| encoder.finish().map(|_| ()),
+                }),
+            Encoder::Gzip =>
+                Box::new(Streaming {
+                    encoder: GzEncoder::new(buf.clone(), Compression::new(level)),
+                    buf,
+                    finish: |encoder| encoder.finish().map(|_| ()),
+                }),
+            // HTTP's "deflate" is actually the zlib format.
+            // <https://tools.ietf.org/html/rfc7230#section-4.2.2>
+            Encoder::Deflate =>
+                Box::new(Streaming {
+                    encoder: ZlibEncoder::new(buf.clone(), Compression::new(level)),
+                    buf,
+                    finish: |encoder| encoder.finish().map(|_| ()),
+                }),
+        };
+        Ok(codec)
+    }
+}
+
+// How much input we'll let the encoder sit on while the body is stalled before we
+// force it to emit what it has, so that slow bodies still trickle out to the client.
+const FLUSH_THRESHOLD: usize = 8_192;
+
+/// Transforms the body into a single stream compressed with the given encoder.
 //
 // TODO: Convert back to Stream -> Stream transformation when Hyper 0.12.x releases
 // since it'll have Body::wrap_stream().
-pub fn gzip(pool: &CpuPool, level: Compression, body: Body) -> Body {
-    let stream = body.and_then(move |chunk| {
-        let mut encoder = GzEncoder::new(Vec::new(), level);
-        encoder
-            .write_all(&chunk)
-            .and_then(|_| encoder.finish())
-            .map(|vec| vec.into())
-            .map_err(|e| e.into())
-    });
-
-    let (tx, body) = Body::pair();
-
-    pool.spawn(tx.send_all(stream.map(Ok).map_err(|_| unreachable!())))
-        .forget();
-
-    body
+pub fn encode(pool: &CpuPool, encoder: Encoder, level: u32, body: Body) -> Body {
+    let codec = match encoder.codec(level) {
+        Ok(codec) => codec,
+        Err(e) => {
+            error!("failed to create {} encoder: {}", encoder.name(), e);
+            return util::spawn_body(pool, stream::once(Err(hyper::Error::from(e))));
+        }
+    };
+
+    util::spawn_body(pool, Encoded { body, codec: Some(codec), pending: 0 })
+}
+
+// PRIVATE
+
+// Where the encoders write their output so that we can drain it after each chunk.
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Buffer {
+    fn take(&self) -> Vec<u8> {
+        let mut vec = self.0.lock().unwrap();
+        ::std::mem::take(&mut *vec)
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// A stateful encoder that runs across the whole body.
+trait Codec: Send {
+    // Feed the encoder and return whatever compressed output is ready.
+    fn encode(&mut self, bytes: &[u8]) -> io::Result<Vec<u8>>;
+    // Force the encoder to emit everything it has been fed so far.
+    fn flush(&mut self) -> io::Result<Vec<u8>>;
+    // Finish the stream and return the remaining output.
+    fn finish(self: Box<Self>) -> io::Result<Vec<u8>>;
+}
+
+struct Streaming<W> {
+    encoder: W,
+    buf: Buffer,
+    finish: fn(W) -> io::Result<()>,
+}
+
+impl<W: Write + Send> Codec for Streaming<W> {
+    fn encode(&mut self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        self.encoder.write_all(bytes)?;
+        Ok(self.buf.take())
+    }
+
+    fn flush(&mut self) -> io::Result<Vec<u8>> {
+        self.encoder.flush()?;
+        Ok(self.buf.take())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<Vec<u8>> {
+        let Streaming { encoder, buf, finish } = *self;
+        finish(encoder)?;
+        Ok(buf.take())
+    }
+}
+
+struct Encoded {
+    body: Body,
+    // None once the stream has finished or failed.
+    codec: Option<Box<dyn Codec>>,
+    // Bytes fed to the encoder that it hasn't emitted yet.
+    pending: usize,
+}
+
+impl Stream for Encoded {
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        loop {
+            if self.codec.is_none() {
+                return Ok(Async::Ready(None));
+            }
+
+            let item = match self.body.poll() {
+                Ok(Async::Ready(item)) => item,
+                Ok(Async::NotReady) => {
+                    if self.pending < FLUSH_THRESHOLD {
+                        return Ok(Async::NotReady);
+                    }
+                    self.pending = 0;
+                    let bytes = self.with_codec(|codec| codec.flush())?;
+                    if bytes.is_empty() {
+                        return Ok(Async::NotReady);
+                    }
+                    return Ok(Async::Ready(Some(Chunk::from(bytes))));
+                }
+                Err(e) => {
+                    self.codec = None;
+                    return Err(e);
+                }
+            };
+
+            match item {
+                Some(chunk) => {
+                    let bytes = self.with_codec(|codec| codec.encode(&chunk))?;
+                    // Encoder is still buffering, so wait for more input.
+                    if bytes.is_empty() {
+                        self.pending += chunk.len();
+                        continue;
+                    }
+                    self.pending = 0;
+                    return Ok(Async::Ready(Some(Chunk::from(bytes))));
+                }
+                None => {
+                    let codec = self.codec.take().unwrap();
+                    return Ok(Async::Ready(Some(Chunk::from(codec.finish()?))));
+                }
+            }
+        }
+    }
+}
+
+impl Encoded {
+    // Any encoder error ends the stream since its state is no longer trustworthy.
+    fn with_codec<F>(&mut self, f: F) -> io::Result<Vec<u8>>
+        where F: FnOnce(&mut Box<dyn Codec>) -> io::Result<Vec<u8>>
+    {
+        let result = match self.codec {
+            None => return Ok(Vec::new()),
+            Some(ref mut codec) => f(codec),
+        };
+        if result.is_err() {
+            self.codec = None;
+        }
+        result
+    }
 }
@@ -1,28 +1,75 @@
 use std::net::SocketAddr;
-use std::error::Error;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::fs;
 use std::iter::FromIterator;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use serde;
 use regex::Regex;
 use unicase::Ascii;
-use hyper::{header, Method};
+use hyper::{header, Method, StatusCode};
 use url::{self, Url};
 
+pub use compress::Encoder;
+use etag;
+use glob::Glob;
+use redirect;
+use path;
+use lookup;
+use jwt;
+
 #[derive(Deserialize, Debug, Clone, Default)]
 pub struct Config {
     pub server: Server,
-    pub gzip: Option<Gzip>,
+    pub compress: Option<Compress>,
     pub log: Option<Log>,
     pub cors: Option<Cors>,
     pub browse: Option<Browse>,
+    pub file_cache: Option<FileCache>,
+    pub etag: Option<ETag>,
+    pub error_pages: Option<ErrorPages>,
+    pub cache: Option<Cache>,
+    // [[headers]] rules
+    #[serde(default)]
+    pub headers: Vec<HeaderRule>,
+    // [[redirect]] rules
+    #[serde(default)]
+    pub redirect: Vec<Redirect>,
+    // [[rewrite]] rules
+    #[serde(default)]
+    pub rewrite: Vec<Rewrite>,
+    pub access: Option<Access>,
+    pub auth: Option<Auth>,
+    pub jwt: Option<Jwt>,
+    pub signed_urls: Option<SignedUrls>,
+    // [gzip] was renamed to [compress]. Unknown tables are ignored, so without
+    // this an old config would quietly stop compressing.
+    #[serde(default, deserialize_with = "renamed_gzip")]
+    #[allow(dead_code)]
+    gzip: Option<()>,
+}
+
+fn renamed_gzip<'de, D>(_: D) -> Result<Option<()>, D::Error> where D: serde::Deserializer<'de> {
+    use serde::de::Error;
+    Err(D::Error::custom("[gzip] was renamed to [compress], see the README"))
 }
 
 #[derive(Debug, Clone)]
 pub struct Server {
     pub root: PathBuf,
     pub addr: SocketAddr,
+    // Files to serve for a directory request, in order of preference.
+    pub index: Vec<String>,
+    // Fallback chain for paths that don't exist, e.g. ["$uri.html", "/index.html"]
+    pub try_files: Vec<String>,
+    // Let paths with a file extension 404 instead of falling back, e.g. a missing .js file.
+    pub try_files_skip_assets: bool,
+    pub dotfiles: path::Dotfiles,
+    pub symlinks: path::Symlinks,
+    // Fall back to matching names in NFC when there's no exact match.
+    pub unicode_normalization: lookup::UnicodeNormalization,
+    // Fall back to matching names regardless of case when there's no exact match.
+    pub case_insensitive: bool,
 }
 
 impl Default for Server {
@@ -30,6 +77,13 @@ impl Default for Server {
         Server {
             root: default_root(),
             addr: default_addr().parse().unwrap(),
+            index: default_index(),
+            try_files: Vec::new(),
+            try_files_skip_assets: true,
+            dotfiles: path::Dotfiles::default(),
+            symlinks: path::Symlinks::default(),
+            unicode_normalization: lookup::UnicodeNormalization::default(),
+            case_insensitive: false,
         }
     }
 }
@@ -46,19 +100,649 @@ fn default_port() -> u32 {
     3000
 }
 
-#[derive(Deserialize, Debug, Clone)]
-pub struct Gzip {
-    // Gzip: 0-9
-    // We default 1 because it has the maximum compression to cpu ratio.
-    // pub level: _,
-    #[serde(default = "default_threshold")]
+fn default_index() -> Vec<String> {
+    vec!["index.html".to_string(), "index.htm".to_string()]
+}
+
+fn default_try_files_skip_assets() -> bool {
+    true
+}
+
+#[derive(Debug, Clone)]
+pub struct Compress {
+    // Encoders we're willing to use, in order of our preference.
+    pub encoders: Vec<Encoder>,
+    // Per-encoder level, e.g. `levels = { gzip = 6, br = 5 }`.
+    // Anything missing uses Encoder::default_level.
+    pub levels: HashMap<Encoder, u32>,
     pub threshold: u64,
+    // Serve app.js.br, app.js.gz, etc. in place of app.js when they exist.
+    pub precompressed: bool,
+}
+
+impl Compress {
+    pub fn level(&self, encoder: Encoder) -> u32 {
+        self.levels.get(&encoder).cloned().unwrap_or_else(|| encoder.default_level())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Compress {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        #[derive(Deserialize, Debug)]
+        struct Compress_ {
+            #[serde(default = "default_encoders")]
+            encoders: Vec<Encoder>,
+            // toml map keys are always strings, so these are encoder names
+            #[serde(default)]
+            levels: HashMap<String, u32>,
+            #[serde(default = "default_threshold")]
+            threshold: u64,
+            #[serde(default)]
+            precompressed: bool,
+        }
+
+        let input = Compress_::deserialize(deserializer)?;
+
+        let mut levels = HashMap::new();
+        for (name, level) in input.levels {
+            let encoder = match default_encoders().into_iter().find(|encoder| encoder.name() == name) {
+                None =>
+                    return Err(D::Error::unknown_variant(&name, &["br", "zstd", "gzip", "deflate"])),
+                Some(encoder) =>
+                    encoder,
+            };
+
+            // The codecs would clamp or reject these on every response instead
+            let range = encoder.levels();
+            if !range.contains(&level) {
+                return Err(D::Error::invalid_value(
+                    serde::de::Unexpected::Unsigned(u64::from(level)),
+                    &format!("a {} level from {} to {}", name, range.start(), range.end()).as_str(),
+                ));
+            }
+
+            levels.insert(encoder, level);
+        }
+
+        Ok(Compress {
+            encoders: input.encoders,
+            levels,
+            threshold: input.threshold,
+            precompressed: input.precompressed,
+        })
+    }
+}
+
+fn default_encoders() -> Vec<Encoder> {
+    vec![Encoder::Brotli, Encoder::Zstd, Encoder::Gzip, Encoder::Deflate]
 }
 
 fn default_threshold() -> u64 {
     1400
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct FileCache {
+    // Total bytes of file bodies to keep in memory.
+    #[serde(default = "default_file_cache_max_size")]
+    pub max_size: u64,
+    // Files larger than this are never cached.
+    #[serde(default = "default_file_cache_max_file_size")]
+    pub max_file_size: u64,
+}
+
+fn default_file_cache_max_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_file_cache_max_file_size() -> u64 {
+    256 * 1024
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ETag {
+    #[serde(default)]
+    pub strategy: etag::Strategy,
+}
+
+// Status code -> page to serve in its place, e.g. `404 = "/404.html"`
+#[derive(Debug, Clone, Default)]
+pub struct ErrorPages {
+    pub pages: HashMap<u16, String>,
+}
+
+impl<'de> serde::Deserialize<'de> for ErrorPages {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        // TOML table keys are always strings
+        let input = HashMap::<String, String>::deserialize(deserializer)?;
+
+        let pages = input.into_iter().map(|(status, page)| {
+            let status = status.parse::<u16>()
+                .ok()
+                .filter(|status| *status >= 400 && *status < 600)
+                .ok_or_else(|| D::Error::invalid_value(
+                    serde::de::Unexpected::Str(&status),
+                    &"a 4xx or 5xx status code",
+                ))?;
+            if !page.starts_with('/') {
+                return Err(D::Error::invalid_value(
+                    serde::de::Unexpected::Str(&page),
+                    &"a path starting with /",
+                ));
+            }
+            Ok((status, page))
+        }).collect::<Result<HashMap<u16, String>, _>>()?;
+
+        Ok(ErrorPages { pages })
+    }
+}
+
+impl ErrorPages {
+    // Every page has to be a file under root. A directory would open just fine
+    // and then fail halfway through the response.
+    pub fn check(&self, root: &Path) -> Result<(), String> {
+        for (status, page) in &self.pages {
+            let page_path = path::get_entity_path(root, page, path::Dotfiles::Allow, &::access::Access::default())
+                .map_err(|_| format!("page for {} is not a valid path: {:?}", status, page))?;
+            match fs::metadata(&page_path) {
+                Ok(ref metadata) if metadata.is_file() => {}
+                Ok(_) => return Err(format!("page for {} is not a file: {:?}", status, page_path)),
+                Err(e) => return Err(format!("page for {} can't be read: {:?}: {}", status, page_path, e)),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cache {
+    // First matching rule wins.
+    pub rules: Vec<CacheRule>,
+    // For paths that no rule matches.
+    pub default: Option<header::CacheControl>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheRule {
+    pub glob: Glob,
+    pub cache_control: header::CacheControl,
+}
+
+impl Cache {
+    // path is the decoded request path
+    pub fn cache_control(&self, path: &str) -> Option<&header::CacheControl> {
+        self.rules.iter()
+            .find(|rule| rule.glob.is_match(path))
+            .map(|rule| &rule.cache_control)
+            .or(self.default.as_ref())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Cache {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        #[derive(Deserialize, Debug)]
+        struct Cache_ {
+            // Shorthand for `default = "public, max-age=<max_age>"`
+            max_age: Option<u32>,
+            default: Option<String>,
+            #[serde(default)]
+            rules: Vec<CacheRule_>,
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct CacheRule_ {
+            glob: Glob,
+            cache_control: String,
+        }
+
+        fn parse_cache_control(s: &str) -> Result<header::CacheControl, String> {
+            header::Header::parse_header(&header::Raw::from(s.to_string()))
+                .map_err(|_| format!("invalid cache_control {:?}", s))
+        }
+
+        let input = Cache_::deserialize(deserializer)?;
+
+        let default = match (input.default, input.max_age) {
+            (Some(_), Some(_)) =>
+                return Err(D::Error::custom("use either default or max_age, not both")),
+            (Some(s), None) =>
+                Some(parse_cache_control(&s).map_err(D::Error::custom)?),
+            (None, Some(max_age)) =>
+                Some(header::CacheControl(vec![
+                    header::CacheDirective::Public,
+                    header::CacheDirective::MaxAge(max_age),
+                ])),
+            (None, None) =>
+                None,
+        };
+
+        let rules = input.rules.into_iter().map(|rule| {
+            Ok(CacheRule {
+                glob: rule.glob,
+                cache_control: parse_cache_control(&rule.cache_control).map_err(D::Error::custom)?,
+            })
+        }).collect::<Result<Vec<CacheRule>, D::Error>>()?;
+
+        Ok(Cache { rules, default })
+    }
+}
+
+// A token, so it can't smuggle in a separator or a second header.
+// <https://tools.ietf.org/html/rfc7230#section-3.2.6>
+pub fn is_header_name(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+pub fn is_header_value(s: &str) -> bool {
+    !s.bytes().any(|b| b == b'\r' || b == b'\n')
+}
+
+#[derive(Debug, Clone)]
+pub struct HeaderRule {
+    pub glob: Glob,
+    // Replaces any existing values
+    pub set: Vec<(String, String)>,
+    // Adds a value alongside any existing ones, e.g. another Link
+    pub append: Vec<(String, String)>,
+    pub remove: Vec<String>,
+}
+
+impl<'de> serde::Deserialize<'de> for HeaderRule {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        #[derive(Deserialize, Debug)]
+        struct HeaderRule_ {
+            glob: Glob,
+            #[serde(default)]
+            set: HashMap<String, String>,
+            #[serde(default)]
+            append: HashMap<String, String>,
+            #[serde(default)]
+            remove: Vec<String>,
+        }
+
+        fn check_name<E: Error>(name: &str) -> Result<(), E> {
+            if is_header_name(name) {
+                Ok(())
+            } else {
+                Err(E::invalid_value(serde::de::Unexpected::Str(name), &"a header name"))
+            }
+        }
+
+        // Sorted so that rules apply in the same order on every run
+        fn pairs<E: Error>(map: HashMap<String, String>) -> Result<Vec<(String, String)>, E> {
+            let mut pairs = Vec::with_capacity(map.len());
+            for (name, value) in map {
+                check_name(&name)?;
+                if !is_header_value(&value) {
+                    return Err(E::invalid_value(serde::de::Unexpected::Str(&value), &"a header value without newlines"));
+                }
+                pairs.push((name, value));
+            }
+            pairs.sort();
+            Ok(pairs)
+        }
+
+        let input = HeaderRule_::deserialize(deserializer)?;
+
+        for name in &input.remove {
+            check_name(name)?;
+        }
+
+        Ok(HeaderRule {
+            glob: input.glob,
+            set: pairs(input.set)?,
+            append: pairs(input.append)?,
+            remove: input.remove,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Redirect {
+    pub rule: redirect::Rule,
+    pub status: StatusCode,
+}
+
+#[derive(Debug, Clone)]
+pub struct Rewrite {
+    pub rule: redirect::Rule,
+}
+
+#[derive(Deserialize, Debug)]
+struct RedirectRule_ {
+    from: String,
+    to: String,
+    #[serde(default)]
+    query: HashMap<String, String>,
+    #[serde(default = "default_redirect_status")]
+    status: u16,
+}
+
+fn default_redirect_status() -> u16 {
+    301
+}
+
+impl RedirectRule_ {
+    fn into_rule<E: serde::de::Error>(self) -> Result<(redirect::Rule, u16), E> {
+        let mut query = Vec::from_iter(self.query);
+        query.sort();
+        let rule = redirect::Rule::new(&self.from, query, self.to).map_err(E::custom)?;
+        Ok((rule, self.status))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Redirect {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let (rule, status) = RedirectRule_::deserialize(deserializer)?.into_rule()?;
+
+        let status = match status {
+            301 => StatusCode::MovedPermanently,
+            302 => StatusCode::Found,
+            307 => StatusCode::TemporaryRedirect,
+            308 => StatusCode::PermanentRedirect,
+            _ =>
+                return Err(D::Error::invalid_value(
+                    serde::de::Unexpected::Unsigned(u64::from(status)),
+                    &"301, 302, 307, or 308",
+                )),
+        };
+
+        Ok(Redirect { rule, status })
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Rewrite {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let (rule, _) = RedirectRule_::deserialize(deserializer)?.into_rule()?;
+
+        // Rewrites are served from our own root
+        if !rule.to.starts_with('/') {
+            return Err(D::Error::invalid_value(
+                serde::de::Unexpected::Str(&rule.to),
+                &"a path starting with /",
+            ));
+        }
+
+        Ok(Rewrite { rule })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Access {
+    // Paths to hide, e.g. "**/*.map"
+    #[serde(default)]
+    pub deny: Vec<Glob>,
+    // Exceptions to deny and .hunkignore
+    #[serde(default)]
+    pub allow: Vec<Glob>,
+}
+
+// HTTP Basic auth against an Apache htpasswd file
+#[derive(Debug, Clone)]
+pub struct Auth {
+    pub htpasswd: PathBuf,
+    // With no rules, every path needs a login for the default realm.
+    // Otherwise only paths matching a rule do, and the first matching rule wins.
+    pub rules: Vec<AuthRule>,
+    pub realm: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct AuthRule {
+    pub glob: Glob,
+    pub realm: String,
+    // Only these users from the htpasswd file, or any of them if None.
+    pub users: Option<Vec<String>>,
+}
+
+impl Auth {
+    // The realm and allowed users for a path, or None if it doesn't need a login.
+    // path is the decoded request path.
+    pub fn protect(&self, path: &str) -> Option<(&str, Option<&[String]>)> {
+        if self.rules.is_empty() {
+            return Some((&self.realm, None));
+        }
+        self.rules.iter()
+            .find(|rule| rule.glob.is_match(path))
+            .map(|rule| (rule.realm.as_str(), rule.users.as_deref()))
+    }
+}
+
+fn default_auth_realm() -> String {
+    "Restricted".to_string()
+}
+
+impl<'de> serde::Deserialize<'de> for Auth {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        #[derive(Deserialize, Debug)]
+        struct Auth_ {
+            htpasswd: PathBuf,
+            #[serde(default = "default_auth_realm")]
+            realm: String,
+            #[serde(default)]
+            rules: Vec<AuthRule_>,
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct AuthRule_ {
+            glob: Glob,
+            realm: Option<String>,
+            users: Option<Vec<String>>,
+        }
+
+        // The realm goes in a quoted-string in the WWW-Authenticate challenge
+        fn check_realm<E: Error>(realm: String) -> Result<String, E> {
+            if realm.chars().any(|c| c == '"' || c == '\\' || c.is_control()) {
+                return Err(E::invalid_value(
+                    serde::de::Unexpected::Str(&realm),
+                    &"a realm without quotes, backslashes, or control characters",
+                ));
+            }
+            Ok(realm)
+        }
+
+        let input = Auth_::deserialize(deserializer)?;
+
+        let realm = check_realm(input.realm)?;
+
+        let rules = input.rules.into_iter().map(|rule| {
+            Ok(AuthRule {
+                glob: rule.glob,
+                realm: check_realm(rule.realm.unwrap_or_else(|| realm.clone()))?,
+                users: rule.users,
+            })
+        }).collect::<Result<Vec<AuthRule>, D::Error>>()?;
+
+        Ok(Auth { htpasswd: input.htpasswd, rules, realm })
+    }
+}
+
+// Bearer token auth with JWTs, e.g. the ones an API hands out
+#[derive(Debug, Clone)]
+pub struct Jwt {
+    pub algorithm: jwt::Algorithm,
+    // PEM or JWK (Set) for RS256, a JWK or the raw secret for HS256
+    pub key: PathBuf,
+    // Also look for the token in this cookie
+    pub cookie: Option<String>,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    // Seconds of clock skew to allow when checking exp and nbf
+    pub leeway: u64,
+    // The longest matching prefix wins. Paths that no rule matches only need a valid token.
+    pub rules: Vec<JwtRule>,
+}
+
+#[derive(Debug, Clone)]
+pub struct JwtRule {
+    pub prefix: String,
+    // Dotted path into the claims, e.g. "realm_access.roles"
+    pub claim: String,
+    // The claim (or one of its items, if it's an array) has to be one of these
+    pub values: Vec<String>,
+}
+
+impl Jwt {
+    // path is the decoded request path
+    pub fn rule(&self, path: &str) -> Option<&JwtRule> {
+        self.rules.iter()
+            .filter(|rule| under_prefix(path, &rule.prefix))
+            .max_by_key(|rule| rule.prefix.trim_end_matches('/').len())
+    }
+}
+
+// "/finance" and "/finance/" both cover /finance and /finance/q1.html, but not /financed.html
+fn under_prefix(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    path == prefix || path.starts_with(&format!("{}/", prefix))
+}
+
+impl<'de> serde::Deserialize<'de> for Jwt {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        #[derive(Deserialize, Debug)]
+        struct Jwt_ {
+            algorithm: jwt::Algorithm,
+            key: PathBuf,
+            cookie: Option<String>,
+            issuer: Option<String>,
+            audience: Option<String>,
+            #[serde(default)]
+            leeway: u64,
+            #[serde(default)]
+            rules: Vec<JwtRule_>,
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct JwtRule_ {
+            prefix: String,
+            claim: String,
+            values: Vec<String>,
+        }
+
+        let input = Jwt_::deserialize(deserializer)?;
+
+        let rules = input.rules.into_iter().map(|rule| {
+            if !rule.prefix.starts_with('/') {
+                return Err(D::Error::invalid_value(
+                    serde::de::Unexpected::Str(&rule.prefix),
+                    &"a path prefix starting with /",
+                ));
+            }
+            if rule.values.is_empty() {
+                return Err(D::Error::custom(format!("no values for the {:?} rule", rule.prefix)));
+            }
+            Ok(JwtRule { prefix: rule.prefix, claim: rule.claim, values: rule.values })
+        }).collect::<Result<Vec<JwtRule>, D::Error>>()?;
+
+        Ok(Jwt {
+            algorithm: input.algorithm,
+            key: input.key,
+            cookie: input.cookie,
+            issuer: input.issuer,
+            audience: input.audience,
+            leeway: input.leeway,
+            rules,
+        })
+    }
+}
+
+// Paths that need an HMAC-signed, expiring query string
+#[derive(Debug, Clone)]
+pub struct SignedUrls {
+    // File holding the shared secret
+    pub key: PathBuf,
+    // Default = ["/"], i.e. everything
+    pub prefixes: Vec<String>,
+}
+
+impl SignedUrls {
+    // path is the decoded request path
+    pub fn protects(&self, path: &str) -> bool {
+        self.prefixes.iter().any(|prefix| under_prefix(path, prefix))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for SignedUrls {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        #[derive(Deserialize, Debug)]
+        struct SignedUrls_ {
+            key: PathBuf,
+            prefixes: Option<Vec<String>>,
+        }
+
+        let input = SignedUrls_::deserialize(deserializer)?;
+
+        let prefixes = input.prefixes.unwrap_or_else(|| vec!["/".to_string()]);
+        if let Some(prefix) = prefixes.iter().find(|prefix| !prefix.starts_with('/')) {
+            return Err(D::Error::invalid_value(
+                serde::de::Unexpected::Str(prefix),
+                &"a path prefix starting with /",
+            ));
+        }
+
+        Ok(SignedUrls { key: input.key, prefixes })
+    }
+}
+
+#[test]
+fn test_gzip_was_renamed() {
+    use toml;
+
+    let err = toml::from_str::<Config>("[server]\n[gzip]\nthreshold = 1000").unwrap_err().to_string();
+    assert!(err.contains("[gzip] was renamed to [compress]"), "{}", err);
+    toml::from_str::<Config>("[server]\n[compress]\nthreshold = 1000").unwrap();
+}
+
+#[test]
+fn test_compress_levels() {
+    use toml;
+
+    let compress = |text: &str| toml::from_str::<Compress>(text);
+
+    assert_eq!(compress("levels = { gzip = 9, br = 11, zstd = 22 }").unwrap().level(Encoder::Brotli), 11);
+    assert_eq!(compress("").unwrap().level(Encoder::Gzip), 1);
+    assert!(compress("levels = { gzip = 10 }").is_err());
+    assert!(compress("levels = { br = 12 }").is_err());
+    assert!(compress("levels = { zstd = 0 }").is_err());
+    assert!(compress("levels = { lzma = 1 }").is_err());
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Log {
     #[serde(default = "default_log_format")]
@@ -86,6 +770,20 @@ impl<'de> serde::Deserialize<'de> for Server {
             root: PathBuf,
             #[serde(default = "default_addr")]
             addr: String,
+            #[serde(default = "default_index")]
+            index: Vec<String>,
+            #[serde(default)]
+            try_files: Vec<String>,
+            #[serde(default = "default_try_files_skip_assets")]
+            try_files_skip_assets: bool,
+            #[serde(default)]
+            dotfiles: path::Dotfiles,
+            #[serde(default)]
+            symlinks: path::Symlinks,
+            #[serde(default)]
+            unicode_normalization: lookup::UnicodeNormalization,
+            #[serde(default)]
+            case_insensitive: bool,
         }
 
         let input = Http_::deserialize(deserializer)?;
@@ -103,7 +801,7 @@ impl<'de> serde::Deserialize<'de> for Server {
             Err(e) => {
                 return Err(D::Error::invalid_value(
                     serde::de::Unexpected::Str(&input.addr),
-                    &e.description(),
+                    &e.to_string().as_str(),
                 ))
             }
             Ok(addr) => addr,
@@ -113,6 +811,13 @@ impl<'de> serde::Deserialize<'de> for Server {
             addr,
             // TODO: Handle error on canonicalize
             root: input.root.canonicalize().unwrap(),
+            index: input.index,
+            try_files: input.try_files,
+            try_files_skip_assets: input.try_files_skip_assets,
+            dotfiles: input.dotfiles,
+            symlinks: input.symlinks,
+            unicode_normalization: input.unicode_normalization,
+            case_insensitive: input.case_insensitive,
         })
     }
 }
@@ -173,7 +878,7 @@ impl<'de> serde::Deserialize<'de> for Origin {
                 )),
             Ok(Origin_::Arr(xs)) => {
                 let origins = xs.into_iter()
-                    .map(|x| url_to_origin(&x).map_err(|e| D::Error::custom(e.description())))
+                    .map(|x| url_to_origin(&x).map_err(|e| D::Error::custom(e.to_string())))
                     .collect::<Result<Vec<header::Origin>, _>>()?;
                 Ok(Origin::Few(origins))
             },
@@ -214,7 +919,7 @@ impl<'de> serde::Deserialize<'de> for Cors {
         let input = Cors_::deserialize(deserializer)?;
 
         let methods = input.methods.into_iter().map(|s| {
-            s.parse::<Method>().map_err(|e| D::Error::custom(e.description()))
+            s.parse::<Method>().map_err(|e| D::Error::custom(e.to_string()))
         }).collect::<Result<Vec<Method>, _>>()?;
 
         let allowed_headers = HashSet::from_iter(input.allowed_headers.into_iter().map(Ascii::new));
@@ -1,6 +1,7 @@
 use colored::Colorize;
 
 use config::{self, Config};
+use lookup;
 
 pub fn pretty(config: &Config) {
     // SERVER
@@ -17,14 +18,40 @@ pub fn pretty(config: &Config) {
         "http://".bright_white(),
         config.server.addr.to_string().bright_white().bold()
     );
+    println!("dotfiles: {}", config.server.dotfiles.name().bright_white().bold());
+    println!("symlinks: {}", config.server.symlinks.name().bright_white().bold());
+    let lookup_mode = match (config.server.unicode_normalization, config.server.case_insensitive) {
+        (lookup::UnicodeNormalization::None, false) => "exact".to_string(),
+        (normalization, case_insensitive) => {
+            let mut modes = Vec::new();
+            if normalization != lookup::UnicodeNormalization::None {
+                modes.push(normalization.name());
+            }
+            if case_insensitive {
+                modes.push("case-insensitive");
+            }
+            modes.join(",")
+        }
+    };
+    println!("lookup: {}", lookup_mode.as_str().bright_white().bold());
 
-    // GZIP
+    // COMPRESS
 
     println!(
-        "- gzip: {}",
-        match config.gzip.as_ref() {
+        "- compress: {}",
+        match config.compress.as_ref() {
             None => "off".red().bold().to_string(),
-            Some(_) => format!("{}", "on".green().bold()),
+            Some(opts) => {
+                let mut s = format!("{}", "on".green().bold());
+                s.push(' ');
+                let encoders = opts.encoders
+                    .iter()
+                    .map(|encoder| format!("{}:{}", encoder.name(), opts.level(*encoder)))
+                    .collect::<Vec<String>>()
+                    .join(",");
+                s.push_str(format!("encoders={}", encoders.bold()).as_ref());
+                s
+            }
         }
     );
 
@@ -73,4 +100,173 @@ pub fn pretty(config: &Config) {
             Some(_) => format!("{}", "on".green().bold()),
         }
     );
-}
\ No newline at end of file
+
+    // FILE CACHE
+
+    println!(
+        "- file_cache: {}",
+        match config.file_cache {
+            None => "off".red().bold().to_string(),
+            Some(ref opts) => {
+                let mut s = format!("{}", "on".green().bold());
+                s.push(' ');
+                s.push_str(&format!("max_size={}", opts.max_size.to_string().bold()));
+                s
+            }
+        }
+    );
+
+    // CACHE
+
+    println!(
+        "- cache: {}",
+        match config.cache.as_ref() {
+            None => "off".red().bold().to_string(),
+            Some(opts) => {
+                let mut s = format!("{}", "on".green().bold());
+                s.push(' ');
+                s.push_str(&format!("rules={}", opts.rules.len().to_string().bold()));
+                if let Some(ref default) = opts.default {
+                    s.push_str(&format!(" default=\"{}\"", default.to_string().bold()));
+                }
+                s
+            }
+        }
+    );
+
+    // HEADERS
+
+    println!(
+        "- headers: {}",
+        if config.headers.is_empty() {
+            "off".red().bold().to_string()
+        } else {
+            let mut s = format!("{}", "on".green().bold());
+            s.push(' ');
+            s.push_str(&format!("rules={}", config.headers.len().to_string().bold()));
+            s
+        }
+    );
+
+    // REDIRECT / REWRITE
+
+    println!(
+        "- redirect: {}",
+        if config.redirect.is_empty() && config.rewrite.is_empty() {
+            "off".red().bold().to_string()
+        } else {
+            let mut s = format!("{}", "on".green().bold());
+            s.push(' ');
+            s.push_str(&format!(
+                "redirects={} rewrites={}",
+                config.redirect.len().to_string().bold(),
+                config.rewrite.len().to_string().bold(),
+            ));
+            s
+        }
+    );
+
+    // ACCESS
+
+    println!(
+        "- access: {}",
+        match config.access.as_ref() {
+            None => format!("{} {}", "off".red().bold(), "(.hunkignore only)"),
+            Some(opts) => {
+                let mut s = format!("{}", "on".green().bold());
+                s.push(' ');
+                s.push_str(&format!(
+                    "deny={} allow={}",
+                    opts.deny.len().to_string().bold(),
+                    opts.allow.len().to_string().bold(),
+                ));
+                s
+            }
+        }
+    );
+
+    // AUTH
+
+    println!(
+        "- auth: {}",
+        match config.auth.as_ref() {
+            None => "off".red().bold().to_string(),
+            Some(opts) => {
+                let mut s = format!("{}", "on".green().bold());
+                s.push(' ');
+                s.push_str(&format!(
+                    "htpasswd={} rules={}",
+                    opts.htpasswd.to_string_lossy().bold(),
+                    if opts.rules.is_empty() { "all".to_string() } else { opts.rules.len().to_string() }.bold(),
+                ));
+                s
+            }
+        }
+    );
+
+    // JWT
+
+    println!(
+        "- jwt: {}",
+        match config.jwt.as_ref() {
+            None => "off".red().bold().to_string(),
+            Some(opts) => {
+                let mut s = format!("{}", "on".green().bold());
+                s.push(' ');
+                s.push_str(&format!(
+                    "algorithm={} key={} rules={}",
+                    opts.algorithm.name().bold(),
+                    opts.key.to_string_lossy().bold(),
+                    opts.rules.len().to_string().bold(),
+                ));
+                s
+            }
+        }
+    );
+
+    // SIGNED URLS
+
+    println!(
+        "- signed_urls: {}",
+        match config.signed_urls.as_ref() {
+            None => "off".red().bold().to_string(),
+            Some(opts) => {
+                let mut s = format!("{}", "on".green().bold());
+                s.push(' ');
+                s.push_str(&format!(
+                    "key={} prefixes={}",
+                    opts.key.to_string_lossy().bold(),
+                    opts.prefixes.join(",").bold(),
+                ));
+                s
+            }
+        }
+    );
+
+    // ETAG
+
+    println!(
+        "- etag: {}",
+        config.etag.as_ref().map(|opts| opts.strategy).unwrap_or_default().name().bold()
+    );
+
+    // ERROR PAGES
+
+    println!(
+        "- error_pages: {}",
+        match config.error_pages.as_ref() {
+            None => "off".red().bold().to_string(),
+            Some(opts) => {
+                let mut s = format!("{}", "on".green().bold());
+                s.push(' ');
+                let mut pages = opts.pages
+                    .iter()
+                    .map(|(status, page)| format!("{}={}", status, page))
+                    .collect::<Vec<String>>();
+                pages.sort();
+                s.push_str(&pages.join(",").bold().to_string());
+                s
+            }
+        }
+    );
+}
@@ -2,90 +2,163 @@ use std::ops::Range;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::io;
 use std::os::unix::fs::{FileExt, MetadataExt};
-use std::fs::File;
+use std::fs::{self, File};
 use std::sync::Arc;
 use std::cmp;
 
 use futures_cpupool::CpuPool;
-use futures::{stream, Sink, Stream};
+use futures::{stream, Future, Stream};
 use hyper::{self, header, Body, Chunk};
 
-use base36;
 use mime;
 use util;
 
+// Identifies a specific version of a file on disk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Validator {
+    pub inode: u64,
+    pub len: u64,
+    pub mtime: SystemTime,
+}
+
+impl Validator {
+    pub fn from_metadata(m: &fs::Metadata) -> Result<Self, io::Error> {
+        Ok(Validator {
+            inode: m.ino(),
+            len: m.len(),
+            mtime: m.modified()?,
+        })
+    }
+}
+
+enum Source {
+    File(File),
+    // Body already in memory, e.g. from the file cache.
+    Memory(Arc<Vec<u8>>),
+}
+
 struct Inner {
-    inode: u64,
-    len: u64,
-    mtime: SystemTime,
+    validator: Validator,
     content_type: mime::MimeRecord,
-    file: File,
+    source: Source,
     pool: CpuPool,
 }
 
+const READ_CHUNK_SIZE: u64 = 65_536;
+
+pub type ChunkStream = Box<dyn Stream<Item = Chunk, Error = hyper::Error> + Send>;
+
 #[derive(Clone)]
 pub struct Entity {
     inner: Arc<Inner>,
 }
 
-#[allow(dead_code)]
-pub enum ETagKind {
-    Strong,
-    Weak,
-}
-
 impl Entity {
     pub fn new(
         file: File,
         pool: CpuPool,
         content_type: mime::MimeRecord,
     ) -> Result<Self, io::Error> {
-        let m = file.metadata()?;
+        let validator = Validator::from_metadata(&file.metadata()?)?;
         Ok(Entity {
             inner: Arc::new(Inner {
-                inode: m.ino(),
-                len: m.len(),
-                mtime: m.modified()?,
-                file,
+                validator,
+                source: Source::File(file),
                 pool,
                 content_type,
             }),
         })
     }
 
+    pub fn from_memory(
+        bytes: Arc<Vec<u8>>,
+        validator: Validator,
+        pool: CpuPool,
+        content_type: mime::MimeRecord,
+    ) -> Self {
+        Entity {
+            inner: Arc::new(Inner {
+                validator,
+                source: Source::Memory(bytes),
+                pool,
+                content_type,
+            }),
+        }
+    }
+
     // This is entity-length, not message-length. e.g. not affected by transfer-encoding.
     pub fn len(&self) -> u64 {
-        self.inner.len
+        self.inner.validator.len
+    }
+
+    pub fn validator(&self) -> &Validator {
+        &self.inner.validator
     }
 
     pub fn content_type(&self) -> &mime::MimeRecord {
         &self.inner.content_type
     }
 
+    // Truncated to whole seconds since that's all an HTTP-date can express, else
+    // a file modified at 12:00:00.5 would look newer than If-Modified-Since 12:00:00.
     pub fn last_modified(&self) -> header::HttpDate {
-        header::HttpDate::from(self.inner.mtime)
-    }
-
-    pub fn etag(&self, kind: &ETagKind) -> header::EntityTag {
-        let dur = self.inner
+        let secs = self.inner
+            .validator
             .mtime
             .duration_since(UNIX_EPOCH)
-            .unwrap_or_else(|_| Duration::new(0, 0));
+            .map(|dur| dur.as_secs())
+            .unwrap_or(0);
+        header::HttpDate::from(UNIX_EPOCH + Duration::from_secs(secs))
+    }
 
-        let tag = format!(
-            "{}${}${}",
-            base36::encode(self.inner.inode),
-            base36::encode(self.len()),
-            base36::encode(util::duration_as_millis(dur))
-        );
+    // Reads the whole entity into memory. Blocks, so only call this from the pool.
+    pub fn read_to_vec(&self) -> Result<Vec<u8>, io::Error> {
+        let mut vec = Vec::with_capacity(self.len() as usize);
+        self.read_chunks(|bytes| vec.extend_from_slice(bytes))?;
+        Ok(vec)
+    }
 
-        match *kind {
-            ETagKind::Strong => header::EntityTag::strong(tag),
-            ETagKind::Weak => header::EntityTag::weak(tag),
+    // Feeds the entity to f a chunk at a time. Blocks, so only call this from the pool.
+    pub fn read_chunks<F>(&self, mut f: F) -> Result<(), io::Error> where F: FnMut(&[u8]) {
+        match self.inner.source {
+            Source::Memory(ref bytes) =>
+                f(bytes),
+            Source::File(ref file) => {
+                let mut buf = vec![0; cmp::min(self.len(), READ_CHUNK_SIZE) as usize];
+                let mut offset = 0;
+                while offset < self.len() {
+                    let want = cmp::min(buf.len() as u64, self.len() - offset) as usize;
+                    match file.read_at(&mut buf[..want], offset)? {
+                        // File shrank underneath us
+                        0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file changed while reading")),
+                        n => {
+                            f(&buf[..n]);
+                            offset += n as u64;
+                        }
+                    }
+                }
+            }
         }
+        Ok(())
     }
 
     pub fn get_range(&self, range: Range<u64>, max_chunk_size: u64) -> Body {
+        match self.inner.source {
+            // Nothing to wait on, so skip the pool.
+            Source::Memory(ref bytes) =>
+                Body::from(bytes[range.start as usize..range.end as usize].to_vec()),
+            Source::File(_) =>
+                self.spawn_body(self.stream_range(range, max_chunk_size)),
+        }
+    }
+
+    // Lazily reads the end-exclusive range from the file in chunks of at most max_chunk_size.
+    pub fn stream_range(&self, range: Range<u64>, max_chunk_size: u64) -> ChunkStream {
+        if let Source::Memory(ref bytes) = self.inner.source {
+            let chunk = Chunk::from(bytes[range.start as usize..range.end as usize].to_vec());
+            return Box::new(stream::once(Ok(chunk)));
+        }
+
         let stream = stream::unfold(
             (range, Arc::clone(&self.inner)),
             move |(remaining, inner)| {
@@ -93,14 +166,23 @@ impl Entity {
                     return None;
                 }
 
+                let file = match inner.source {
+                    Source::File(ref file) => file,
+                    Source::Memory(_) => unreachable!(),
+                };
+
                 // Determine size of next chunk
                 let chunk_size = cmp::min(max_chunk_size, remaining.end - remaining.start) as usize;
 
                 // Read chunk from file
-                let mut chunk = Vec::with_capacity(chunk_size);
-                unsafe { chunk.set_len(chunk_size) };
-                let bytes_read = match inner.file.read_at(&mut chunk, remaining.start) {
+                let mut chunk = vec![0; chunk_size];
+                let bytes_read = match file.read_at(&mut chunk, remaining.start) {
                     Err(e) => return Some(Err(hyper::Error::from(e))),
+                    // File was truncated underneath us. Bail instead of spinning forever.
+                    Ok(0) => return Some(Err(hyper::Error::from(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "file changed while streaming",
+                    )))),
                     Ok(n) => n,
                 };
                 chunk.truncate(bytes_read);
@@ -112,16 +194,20 @@ impl Entity {
             },
         );
 
-        let (tx, body) = Body::pair();
-
-        let future = tx.send_all(stream.map(Ok).map_err(|e| {
-            // TODO: How should I handle this?
-            error!("error while sending get_range stream: {}", e);
-            unimplemented!()
-        }));
-
-        self.inner.pool.spawn(future).forget();
+        Box::new(stream)
+    }
 
-        body
+    // Drive the stream on our pool and hand back the receiving end as a Body.
+    pub fn spawn_body(&self, stream: ChunkStream) -> Body {
+        match self.inner.source {
+            // A stream over memory is always ready, so we can collect it right here.
+            Source::Memory(_) =>
+                match stream.concat2().wait() {
+                    Ok(chunk) => Body::from(chunk),
+                    Err(e) => util::spawn_body(&self.inner.pool, stream::once(Err(e))),
+                },
+            Source::File(_) =>
+                util::spawn_body(&self.inner.pool, stream),
+        }
     }
 }
@@ -0,0 +1,132 @@
+// ETag generation
+//
+// - inode-mtime-size: the default. Cheap, but differs across machines.
+// - mtime-size: survives replicas with identical files (e.g. behind a load balancer)
+//   as long as deploys preserve mtimes.
+// - content-sha256: strong tags that survive redeploys. Computed once per file version.
+// - none: no ETag at all, so clients fall back to Last-Modified.
+
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, UNIX_EPOCH};
+
+use hyper::header;
+use sha2::{Digest, Sha256};
+
+use base36;
+use entity::{Entity, Validator};
+use lru::Lru;
+use util;
+
+// Remember the content hashes of this many files, least recently used out first.
+const MAX_HASHES: u64 = 10_000;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Strategy {
+    #[serde(rename = "inode-mtime-size")]
+    #[default]
+    InodeMtimeSize,
+    #[serde(rename = "mtime-size")]
+    MtimeSize,
+    #[serde(rename = "content-sha256")]
+    ContentSha256,
+    #[serde(rename = "none")]
+    None,
+}
+
+impl Strategy {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Strategy::InodeMtimeSize => "inode-mtime-size",
+            Strategy::MtimeSize => "mtime-size",
+            Strategy::ContentSha256 => "content-sha256",
+            Strategy::None => "none",
+        }
+    }
+}
+
+pub struct ETagger {
+    strategy: Strategy,
+    // path -> (file version we hashed, hash)
+    hashes: Mutex<Lru<(Validator, String)>>,
+}
+
+impl ETagger {
+    pub fn new(strategy: Strategy) -> Self {
+        ETagger {
+            strategy,
+            hashes: Mutex::new(Lru::default()),
+        }
+    }
+
+    // Strong ETag for the entity at path, or None if ETags are disabled.
+    //
+    // The content-sha256 strategy reads the whole file the first time it sees
+    // each version of it, so only call this from the pool unless it's in memory.
+    pub fn etag(&self, path: &Path, entity: &Entity) -> Option<header::EntityTag> {
+        let validator = entity.validator();
+
+        let tag = match self.strategy {
+            Strategy::None =>
+                return None,
+            Strategy::InodeMtimeSize =>
+                format!(
+                    "{}${}${}",
+                    base36::encode(validator.inode),
+                    base36::encode(validator.len),
+                    base36::encode(mtime_millis(validator))
+                ),
+            Strategy::MtimeSize =>
+                format!(
+                    "{}${}",
+                    base36::encode(validator.len),
+                    base36::encode(mtime_millis(validator))
+                ),
+            Strategy::ContentSha256 =>
+                self.content_hash(path, entity)?,
+        };
+
+        Some(header::EntityTag::strong(tag))
+    }
+
+    fn content_hash(&self, path: &Path, entity: &Entity) -> Option<String> {
+        if let Some((validator, hash)) = self.hashes.lock().unwrap().touch(path) {
+            if validator == *entity.validator() {
+                return Some(hash);
+            }
+        }
+
+        let mut hasher = Sha256::default();
+        if let Err(e) = entity.read_chunks(|bytes| hasher.input(bytes)) {
+            error!("could not hash {:?} for its etag: {}", path, e);
+            return None;
+        }
+        // 128 bits is plenty to tell versions of a file apart.
+        let hash: String = format!("{:x}", hasher.result()).chars().take(32).collect();
+
+        self.hashes
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), (entity.validator().clone(), hash.clone()), 1, MAX_HASHES);
+
+        Some(hash)
+    }
+}
+
+// Derived representations, e.g. compressed on the fly, aren't byte-for-byte stable
+// so they only get a weak version of the original's ETag.
+pub fn weaken(headers: &mut ::hyper::Headers) {
+    let weak = match headers.get::<header::ETag>() {
+        Some(etag) if !etag.weak => header::EntityTag::weak(etag.tag().to_string()),
+        _ => return,
+    };
+    headers.set(header::ETag(weak));
+}
+
+fn mtime_millis(validator: &Validator) -> u64 {
+    let dur = validator
+        .mtime
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::new(0, 0));
+    util::duration_as_millis(dur)
+}
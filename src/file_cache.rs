@@ -0,0 +1,137 @@
+// In-memory LRU of small file bodies so that hot files like favicon.ico can be
+// answered on the reactor without opening or reading the file.
+//
+// Entries are keyed by request path and hold what the path resolved to along with
+// the file's precompressed sidecars. A hit only stats the file to check its inode,
+// length, and mtime. Everything else, e.g. [access], symlinks, or a new sidecar,
+// is checked again by a trip through the pool once the entry is RECHECK_INTERVAL_SECS old.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use hyper::header;
+
+use compress::Encoder;
+use config;
+use entity::{Entity, Validator};
+use lru::Lru;
+
+// Log the hit/miss counters every this many lookups.
+const REPORT_EVERY: usize = 1_000;
+
+// Same as the Netlify and .hunkignore watchers.
+const RECHECK_INTERVAL_SECS: u64 = 1;
+
+// The bytes of one representation, e.g. app.js or app.js.br.
+#[derive(Clone)]
+pub struct Variant {
+    pub validator: Validator,
+    pub bytes: Arc<Vec<u8>>,
+    // Computed on the pool when filled since content-sha256 has to hash the file
+    pub etag: Option<header::EntityTag>,
+}
+
+#[derive(Clone)]
+pub struct Cached {
+    // Where the request path resolved to on disk
+    pub path: PathBuf,
+    pub original: Variant,
+    pub sidecars: Vec<(Encoder, Variant)>,
+    filled: Instant,
+}
+
+pub struct FileCache {
+    config: config::FileCache,
+    // Keyed by request path, e.g. /favicon.ico
+    lru: Mutex<Lru<Cached>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl FileCache {
+    pub fn new(config: config::FileCache) -> Self {
+        FileCache {
+            config,
+            lru: Mutex::new(Lru::default()),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    // Counts a request toward the hit/miss stats.
+    pub fn count(&self, hit: bool) {
+        let (hits, misses) = if hit {
+            (self.hits.fetch_add(1, Ordering::Relaxed) + 1, self.misses.load(Ordering::Relaxed))
+        } else {
+            (self.hits.load(Ordering::Relaxed), self.misses.fetch_add(1, Ordering::Relaxed) + 1)
+        };
+        if (hits + misses) % REPORT_EVERY == 0 {
+            info!("file cache: {} hits, {} misses", hits, misses);
+        }
+    }
+
+    // Returns the cached entry if it's recent and the file on disk hasn't changed
+    // since we cached it. Costs one stat() and nothing else.
+    pub fn get(&self, req_path: &str) -> Option<Cached> {
+        let cached = self.lru.lock().unwrap().touch(Path::new(req_path))?;
+
+        if cached.filled.elapsed() >= Duration::from_secs(RECHECK_INTERVAL_SECS) {
+            return None;
+        }
+
+        let current = fs::metadata(&cached.path)
+            .ok()
+            .and_then(|m| Validator::from_metadata(&m).ok());
+
+        if current.as_ref() != Some(&cached.original.validator) {
+            self.lru.lock().unwrap().remove(Path::new(req_path));
+            return None;
+        }
+
+        Some(cached)
+    }
+
+    // Reads and caches the file and its sidecars if they're all small enough.
+    // Blocks, so only call this from the pool.
+    pub fn fill(
+        &self,
+        req_path: &str,
+        path: &Path,
+        original: (&Entity, Option<header::EntityTag>),
+        sidecars: Vec<(Encoder, &Entity, Option<header::EntityTag>)>,
+    ) {
+        let read = |(entity, etag): (&Entity, Option<header::EntityTag>)| {
+            if entity.len() > self.config.max_file_size {
+                return None;
+            }
+            match entity.read_to_vec() {
+                Err(e) => {
+                    debug!("file cache could not read {:?}: {}", path, e);
+                    None
+                }
+                Ok(bytes) =>
+                    Some(Variant { validator: entity.validator().clone(), bytes: Arc::new(bytes), etag }),
+            }
+        };
+
+        // A partial entry would negotiate differently than the file on disk.
+        let original = match read(original) {
+            None => return,
+            Some(body) => body,
+        };
+        let mut bodies = Vec::with_capacity(sidecars.len());
+        for (encoder, entity, etag) in sidecars {
+            match read((entity, etag)) {
+                None => return,
+                Some(body) => bodies.push((encoder, body)),
+            }
+        }
+
+        let size = original.bytes.len() as u64 + bodies.iter().map(|(_, body)| body.bytes.len() as u64).sum::<u64>();
+        let cached = Cached { path: path.to_path_buf(), original, sidecars: bodies, filled: Instant::now() };
+        self.lru.lock().unwrap().insert(PathBuf::from(req_path), cached, size, self.config.max_size);
+    }
+}
@@ -0,0 +1,147 @@
+// Path globs for config rules, e.g. `*.html` or `/assets/**`.
+//
+// - Patterns containing a `/` match the whole request path. A leading `/` is implied.
+// - Patterns without a `/` match the last path segment, e.g. `*.html` matches `/a/b/c.html`.
+// - `*` and `?` never cross a `/`, but `**` does.
+// - `{a,b}` matches either alternative.
+//
+// Globs are compiled to a regex once at config time.
+
+use std::fmt;
+
+use regex::{self, Regex};
+use serde;
+
+#[derive(Clone)]
+pub struct Glob {
+    pattern: String,
+    regex: Regex,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Glob, String> {
+        if pattern.is_empty() {
+            return Err("glob cannot be empty".to_string());
+        }
+
+        let anchored = pattern.contains('/');
+
+        let mut re = String::from("^");
+        if anchored && !pattern.starts_with('/') {
+            re.push('/');
+        }
+
+        let mut depth = 0;
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    // "**/" also matches zero directories, e.g. /**/*.js matches /app.js
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        re.push_str("(?:.*/)?");
+                    } else {
+                        re.push_str(".*");
+                    }
+                }
+                '*' =>
+                    re.push_str("[^/]*"),
+                '?' =>
+                    re.push_str("[^/]"),
+                '{' => {
+                    depth += 1;
+                    re.push_str("(?:");
+                }
+                '}' if depth > 0 => {
+                    depth -= 1;
+                    re.push(')');
+                }
+                ',' if depth > 0 =>
+                    re.push('|'),
+                c =>
+                    re.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+
+        if depth > 0 {
+            return Err(format!("unclosed {{ in glob {:?}", pattern));
+        }
+
+        re.push('$');
+
+        let regex = Regex::new(&re).map_err(|e| e.to_string())?;
+
+        Ok(Glob { pattern: pattern.to_string(), regex })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    // path is a decoded request path, e.g. "/assets/app.js"
+    pub fn is_match(&self, path: &str) -> bool {
+        if self.pattern.contains('/') {
+            self.regex.is_match(path)
+        } else {
+            self.regex.is_match(path.rsplit('/').next().unwrap_or(path))
+        }
+    }
+}
+
+impl fmt::Debug for Glob {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Glob({:?})", self.pattern)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Glob {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let pattern = String::deserialize(deserializer)?;
+        Glob::new(&pattern).map_err(D::Error::custom)
+    }
+}
+
+#[test]
+fn test_is_match() {
+    // (glob, path, expected)
+    let table = vec![
+        ("*.html", "/index.html", true),
+        ("*.html", "/a/b/c.html", true),
+        ("*.html", "/a.html/b", false),
+        ("*.html", "/index.htm", false),
+        ("index.html", "/a/index.html", true),
+        ("/index.html", "/a/index.html", false),
+        ("/assets/**", "/assets/app.js", true),
+        ("/assets/**", "/assets/js/app.js", true),
+        ("/assets/**", "/static/app.js", false),
+        ("assets/**", "/assets/app.js", true),
+        ("/assets/*", "/assets/js/app.js", false),
+        ("/**/*.js", "/app.js", true),
+        ("/**/*.js", "/a/b/app.js", true),
+        ("/**/*.js", "/a/b/app.css", false),
+        ("/a?c", "/abc", true),
+        ("/a?c", "/a/c", false),
+        ("*.{css,js}", "/app.js", true),
+        ("*.{css,js}", "/app.css", true),
+        ("*.{css,js}", "/app.jsx", false),
+        // Regex metacharacters are literal
+        ("/a+b.txt", "/a+b.txt", true),
+        ("/a+b.txt", "/aab.txt", false),
+        ("/a.txt", "/abtxt", false),
+    ];
+
+    for (glob, path, expected) in table {
+        assert_eq!(expected, Glob::new(glob).unwrap().is_match(path), "{:?} {:?}", glob, path);
+    }
+}
+
+#[test]
+fn test_invalid() {
+    assert!(Glob::new("").is_err());
+    assert!(Glob::new("*.{css,js").is_err());
+}
@@ -0,0 +1,262 @@
+// Apache htpasswd files for [auth], e.g. made with `htpasswd -B .htpasswd alice`.
+//
+// <https://httpd.apache.org/docs/2.4/misc/password_encryptions.html>
+//
+// Supports bcrypt ($2y$), apr1 ($apr1$, htpasswd's MD5 default), and SHA1 ({SHA}).
+// Plaintext and crypt(3) lines are skipped with a warning.
+//
+// The file is re-read whenever it changes on disk.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use base64;
+use bcrypt;
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use util::constant_time_eq;
+use watched::Watched;
+
+// Forget every verified password once we're holding this many.
+const MAX_VERIFIED: usize = 1_024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Hash {
+    Bcrypt(String),
+    Apr1 { salt: String, hash: String },
+    // Base64 of the raw SHA1 digest
+    Sha1(String),
+}
+
+impl Hash {
+    pub fn parse(s: &str) -> Result<Hash, String> {
+        if s.starts_with("$2y$") || s.starts_with("$2a$") || s.starts_with("$2b$") {
+            return Ok(Hash::Bcrypt(s.to_string()));
+        }
+
+        if let Some(rest) = s.strip_prefix("$apr1$") {
+            let mut parts = rest.splitn(2, '$');
+            return match (parts.next(), parts.next()) {
+                (Some(salt), Some(hash)) if !salt.is_empty() && !hash.is_empty() =>
+                    Ok(Hash::Apr1 { salt: salt.to_string(), hash: hash.to_string() }),
+                _ =>
+                    Err("malformed apr1 hash".to_string()),
+            };
+        }
+
+        if let Some(hash) = s.strip_prefix("{SHA}") {
+            return Ok(Hash::Sha1(hash.to_string()));
+        }
+
+        Err("unsupported hash, use bcrypt, apr1, or SHA1".to_string())
+    }
+
+    // bcrypt is slow on purpose, so call it from the pool.
+    pub fn verify(&self, password: &str) -> bool {
+        match *self {
+            Hash::Bcrypt(ref hash) =>
+                bcrypt::verify(password, hash).unwrap_or(false),
+            Hash::Apr1 { ref salt, ref hash } =>
+                constant_time_eq(apr1(password.as_bytes(), salt.as_bytes()).as_bytes(), hash.as_bytes()),
+            Hash::Sha1(ref hash) => {
+                let mut hasher = Sha1::default();
+                hasher.input(password.as_bytes());
+                constant_time_eq(base64::encode(hasher.result()).as_bytes(), hash.as_bytes())
+            }
+        }
+    }
+}
+
+// The part of an apr1 hash after the salt.
+// <https://svn.apache.org/viewvc/apr/apr/trunk/crypto/apr_md5.c?view=markup>
+fn apr1(password: &[u8], salt: &[u8]) -> String {
+    const MAGIC: &[u8] = b"$apr1$";
+    const ITOA64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+    let salt = &salt[..salt.len().min(8)];
+
+    let mut alt = Md5::default();
+    alt.input(password);
+    alt.input(salt);
+    alt.input(password);
+    let alt = alt.result();
+
+    let mut ctx = Md5::default();
+    ctx.input(password);
+    ctx.input(MAGIC);
+    ctx.input(salt);
+    for chunk in password.chunks(16) {
+        ctx.input(&alt[..chunk.len()]);
+    }
+    let mut i = password.len();
+    while i > 0 {
+        if i & 1 == 1 {
+            ctx.input([0]);
+        } else {
+            ctx.input(&password[..1]);
+        }
+        i >>= 1;
+    }
+    let mut digest = ctx.result();
+
+    // Stretch it
+    for i in 0..1000 {
+        let mut ctx = Md5::default();
+        if i & 1 == 1 { ctx.input(password) } else { ctx.input(digest) }
+        if i % 3 != 0 { ctx.input(salt) }
+        if i % 7 != 0 { ctx.input(password) }
+        if i & 1 == 1 { ctx.input(digest) } else { ctx.input(password) }
+        digest = ctx.result();
+    }
+
+    // crypt's own base64, three bytes at a time in a scrambled order
+    let mut out = String::with_capacity(22);
+    {
+        let mut to64 = |mut v: u32, n: usize| {
+            for _ in 0..n {
+                out.push(ITOA64[(v & 0x3f) as usize] as char);
+                v >>= 6;
+            }
+        };
+        for &(a, b, c) in &[(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+            to64((u32::from(digest[a]) << 16) | (u32::from(digest[b]) << 8) | u32::from(digest[c]), 4);
+        }
+        to64(u32::from(digest[11]), 2);
+    }
+    out
+}
+
+// e.g. `alice:$apr1$s4Lt3d9z$M61A0r9nRKm9ntGkdILi..`
+pub fn parse_htpasswd(text: &str) -> Vec<(String, Hash)> {
+    let mut users = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.splitn(2, ':');
+        match (parts.next(), parts.next().map(Hash::parse)) {
+            (Some(user), Some(Ok(hash))) if !user.is_empty() =>
+                users.push((user.to_string(), hash)),
+            (_, Some(Err(e))) =>
+                warn!("htpasswd line {}: {}", i + 1, e),
+            _ =>
+                warn!("htpasswd line {}: expected \"user:hash\"", i + 1),
+        }
+    }
+    users
+}
+
+pub struct Htpasswd {
+    users: Watched<Vec<(String, Hash)>>,
+    // Fingerprints of credentials that already passed, so that we only pay for
+    // bcrypt once rather than on every request a browser sends with them.
+    verified: Mutex<HashSet<Vec<u8>>>,
+}
+
+impl fmt::Debug for Htpasswd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Htpasswd({:?})", self.users.path())
+    }
+}
+
+impl Htpasswd {
+    pub fn new(path: PathBuf) -> Self {
+        Htpasswd {
+            users: Watched::new(path, parse_htpasswd),
+            verified: Mutex::new(HashSet::new()),
+        }
+    }
+
+    // Includes the stored hash so that changing a password in the file revokes the old one.
+    fn fingerprint(user: &str, hash: &Hash, password: &str) -> Vec<u8> {
+        let mut hasher = Sha256::default();
+        hasher.input(format!("{:?}", hash).as_bytes());
+        hasher.input([0]);
+        hasher.input(user.as_bytes());
+        hasher.input([0]);
+        hasher.input(password.as_bytes());
+        hasher.result().to_vec()
+    }
+
+    fn find(users: &[(String, Hash)], user: &str) -> Option<Hash> {
+        users.iter()
+            .find(|&(name, _)| name == user)
+            .map(|(_, hash)| hash.clone())
+    }
+
+    // Cheap, so it's fine to call from the reactor. false just means "not yet",
+    // including when the file is due for a reload.
+    pub fn is_verified(&self, user: &str, password: &str) -> bool {
+        let users = match self.users.peek() {
+            None => return false,
+            Some(users) => users,
+        };
+        match Htpasswd::find(&users, user) {
+            None => false,
+            Some(hash) => self.verified.lock().unwrap().contains(&Htpasswd::fingerprint(user, &hash, password)),
+        }
+    }
+
+    // Can be slow, so call it from the pool.
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        let hash = match Htpasswd::find(&self.users.get(), user) {
+            None => return false,
+            Some(hash) => hash,
+        };
+
+        let fingerprint = Htpasswd::fingerprint(user, &hash, password);
+        if self.verified.lock().unwrap().contains(&fingerprint) {
+            return true;
+        }
+
+        if !hash.verify(password) {
+            return false;
+        }
+
+        let mut verified = self.verified.lock().unwrap();
+        if verified.len() >= MAX_VERIFIED {
+            verified.clear();
+        }
+        verified.insert(fingerprint);
+        true
+    }
+}
+
+#[test]
+fn test_verify() {
+    // (hash, password, expected)
+    let table = vec![
+        ("$apr1$s4Lt3d9z$M61A0r9nRKm9ntGkdILi..", "secret", true),
+        ("$apr1$s4Lt3d9z$M61A0r9nRKm9ntGkdILi..", "Secret", false),
+        ("{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=", "secret", true),
+        ("{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=", "", false),
+        // From the crypt_blowfish test vectors
+        ("$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW", "U*U", true),
+        ("$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW", "U*V", false),
+    ];
+
+    for (hash, password, expected) in table {
+        assert_eq!(expected, Hash::parse(hash).unwrap().verify(password), "{:?} {:?}", hash, password);
+    }
+}
+
+#[test]
+fn test_parse_htpasswd() {
+    let users = parse_htpasswd("
+# comment
+alice:$apr1$s4Lt3d9z$M61A0r9nRKm9ntGkdILi..
+bob:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=
+carol:plaintext
+dave
+");
+
+    assert_eq!(users, vec![
+        ("alice".to_string(), Hash::Apr1 { salt: "s4Lt3d9z".to_string(), hash: "M61A0r9nRKm9ntGkdILi..".to_string() }),
+        ("bob".to_string(), Hash::Sha1("5en6G6MezRroT3XKqkdPOmY/BfQ=".to_string())),
+    ]);
+}
@@ -0,0 +1,238 @@
+// Verifies the JWTs that [jwt] lets in.
+//
+// The key is loaded once at startup. RS256 takes a PEM public key or a JWK (Set),
+// e.g. a copy of the issuer's /.well-known/jwks.json. HS256 takes a JWK or a file
+// holding the shared secret.
+//
+// jsonwebtoken checks the signature along with exp, nbf, aud, and iss.
+
+use std::fmt;
+use std::fs;
+
+use jsonwebtoken::{self, jwk, DecodingKey, Validation};
+use serde_json::{self, Value};
+
+use config;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    #[serde(rename = "HS256")]
+    Hs256,
+    #[serde(rename = "RS256")]
+    Rs256,
+}
+
+impl Algorithm {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Algorithm::Hs256 => "HS256",
+            Algorithm::Rs256 => "RS256",
+        }
+    }
+}
+
+pub struct Verifier {
+    // (kid, key). A JWK Set can hold more than one.
+    keys: Vec<(Option<String>, DecodingKey)>,
+    validation: Validation,
+}
+
+impl fmt::Debug for Verifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kids = self.keys.iter().map(|(kid, _)| kid).collect::<Vec<_>>();
+        write!(f, "Verifier({:?})", kids)
+    }
+}
+
+impl Verifier {
+    pub fn new(config: &config::Jwt) -> Result<Verifier, String> {
+        let bytes = fs::read(&config.key)
+            .map_err(|e| format!("could not read jwt key {:?}: {}", config.key, e))?;
+        Verifier::from_key(config, &bytes)
+    }
+
+    fn from_key(config: &config::Jwt, bytes: &[u8]) -> Result<Verifier, String> {
+        let text = String::from_utf8_lossy(bytes);
+        let text = text.trim();
+
+        let keys = if text.starts_with('{') {
+            // A JWK Set, or a lone JWK
+            let jwks = match serde_json::from_str::<jwk::JwkSet>(text) {
+                Ok(set) => set.keys,
+                Err(_) => vec![serde_json::from_str::<jwk::Jwk>(text).map_err(|e| format!("invalid jwk: {}", e))?],
+            };
+            jwks.iter()
+                .map(|jwk| {
+                    let key = DecodingKey::from_jwk(jwk).map_err(|e| format!("invalid jwk: {}", e))?;
+                    Ok((jwk.common.key_id.clone(), key))
+                })
+                .collect::<Result<Vec<_>, String>>()?
+        } else {
+            let key = match config.algorithm {
+                Algorithm::Rs256 =>
+                    DecodingKey::from_rsa_pem(text.as_bytes()).map_err(|e| format!("invalid pem: {}", e))?,
+                // Trailing newlines from `echo secret > file` aren't part of the secret
+                Algorithm::Hs256 =>
+                    DecodingKey::from_secret(text.as_bytes()),
+            };
+            vec![(None, key)]
+        };
+
+        if keys.is_empty() {
+            return Err("no keys in the jwk set".to_string());
+        }
+
+        let mut validation = Validation::new(match config.algorithm {
+            Algorithm::Hs256 => jsonwebtoken::Algorithm::HS256,
+            Algorithm::Rs256 => jsonwebtoken::Algorithm::RS256,
+        });
+        validation.leeway = config.leeway;
+        validation.validate_nbf = true;
+        match config.audience {
+            Some(ref audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        if let Some(ref issuer) = config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+
+        Ok(Verifier { keys, validation })
+    }
+
+    // The token's claims if it checks out.
+    pub fn verify(&self, token: &str) -> Result<Value, String> {
+        let header = jsonwebtoken::decode_header(token).map_err(|e| e.to_string())?;
+
+        // Use the key the token names, else try each of them
+        let keys = self.keys.iter()
+            .filter(|&(kid, _)| header.kid.is_none() || kid.is_none() || *kid == header.kid);
+
+        let mut error = "no key matches the token's kid".to_string();
+        for (_, key) in keys {
+            match jsonwebtoken::decode::<Value>(token, key, &self.validation) {
+                Ok(data) => return Ok(data.claims),
+                Err(e) => error = e.to_string(),
+            }
+        }
+        Err(error)
+    }
+}
+
+// Whether the claims satisfy a rule. claim is a dotted path, e.g. "realm_access.roles".
+pub fn allows(claims: &Value, rule: &config::JwtRule) -> bool {
+    let claim = rule.claim.split('.').try_fold(claims, |value, key| value.get(key));
+
+    let matches = |value: &Value| match *value {
+        Value::String(ref s) => rule.values.contains(s),
+        Value::Bool(_) | Value::Number(_) => rule.values.contains(&value.to_string()),
+        _ => false,
+    };
+
+    match claim {
+        Some(Value::Array(items)) => items.iter().any(matches),
+        Some(value) => matches(value),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+fn test_config() -> config::Jwt {
+    config::Jwt {
+        algorithm: Algorithm::Hs256,
+        key: "jwt.key".into(),
+        cookie: None,
+        issuer: Some("https://auth.example.com".to_string()),
+        audience: Some("dashboards".to_string()),
+        leeway: 0,
+        rules: Vec::new(),
+    }
+}
+
+#[test]
+fn test_verify() {
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    let verifier = Verifier::from_key(&test_config(), b"s3cret\n").unwrap();
+
+    let now = ::std::time::SystemTime::now()
+        .duration_since(::std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let sign = |claims: Value, secret: &[u8]| {
+        encode(&Header::new(jsonwebtoken::Algorithm::HS256), &claims, &EncodingKey::from_secret(secret)).unwrap()
+    };
+
+    let valid = json!({ "sub": "alice", "iss": "https://auth.example.com", "aud": "dashboards", "exp": now + 60 });
+    let with = |key: &str, value: Value| {
+        let mut claims = valid.clone();
+        claims[key] = value;
+        claims
+    };
+
+    // (claims, secret, expected ok)
+    let table = vec![
+        (valid.clone(), &b"s3cret"[..], true),
+        (valid.clone(), &b"wrong"[..], false),
+        (with("exp", json!(now - 60)), &b"s3cret"[..], false),
+        (with("nbf", json!(now + 60)), &b"s3cret"[..], false),
+        (with("nbf", json!(now - 60)), &b"s3cret"[..], true),
+        (with("aud", json!("billing")), &b"s3cret"[..], false),
+        (with("aud", json!(["billing", "dashboards"])), &b"s3cret"[..], true),
+        (with("iss", json!("https://evil.example.com")), &b"s3cret"[..], false),
+    ];
+
+    for (claims, secret, expected) in table {
+        let result = verifier.verify(&sign(claims.clone(), secret));
+        assert_eq!(expected, result.is_ok(), "{} {:?}", claims, result);
+    }
+
+    // exp is required
+    let mut claims = valid.clone();
+    claims.as_object_mut().unwrap().remove("exp");
+    assert!(verifier.verify(&sign(claims, b"s3cret")).is_err());
+
+    assert!(verifier.verify("not.a.token").is_err());
+}
+
+#[test]
+fn test_allows() {
+    let rule = |claim: &str, values: &[&str]| config::JwtRule {
+        prefix: "/finance/".to_string(),
+        claim: claim.to_string(),
+        values: values.iter().map(|x| x.to_string()).collect(),
+    };
+
+    let claims = json!({
+        "sub": "alice",
+        "admin": true,
+        "groups": ["sales", "finance"],
+        "realm_access": { "roles": ["viewer"] },
+    });
+
+    assert!(allows(&claims, &rule("sub", &["alice", "bob"])));
+    assert!(!allows(&claims, &rule("sub", &["bob"])));
+    assert!(allows(&claims, &rule("groups", &["finance"])));
+    assert!(!allows(&claims, &rule("groups", &["hr"])));
+    assert!(allows(&claims, &rule("admin", &["true"])));
+    assert!(allows(&claims, &rule("realm_access.roles", &["viewer"])));
+    assert!(!allows(&claims, &rule("realm_access.missing", &["viewer"])));
+    assert!(!allows(&claims, &rule("realm_access", &["viewer"])));
+}
+
+#[test]
+fn test_rule() {
+    let mut config = test_config();
+    config.rules = vec![
+        config::JwtRule { prefix: "/finance".to_string(), claim: "groups".to_string(), values: vec!["finance".to_string()] },
+        config::JwtRule { prefix: "/finance/reports/".to_string(), claim: "groups".to_string(), values: vec!["audit".to_string()] },
+    ];
+
+    let prefix = |path: &str| config.rule(path).map(|rule| rule.prefix.as_str());
+
+    assert_eq!(prefix("/finance"), Some("/finance"));
+    assert_eq!(prefix("/finance/q1.html"), Some("/finance"));
+    assert_eq!(prefix("/finance/reports/q1.html"), Some("/finance/reports/"));
+    assert_eq!(prefix("/financed.html"), None);
+    assert_eq!(prefix("/"), None);
+}
@@ -1,18 +1,13 @@
 //#![allow(warnings)]
 
-#![feature(conservative_impl_trait)]
-#![feature(macro_at_most_once_rep)]
-#![feature(nll)]
-#![feature(pattern_parentheses)]
-#![feature(option_filter)]
-#![feature(proc_macro)] // For maud
-
 extern crate url;
 extern crate tokio;
 extern crate futures;
 extern crate futures_cpupool;
 extern crate tokio_core;
 extern crate flate2;
+extern crate brotli;
+extern crate zstd;
 extern crate hyper;
 extern crate maud;
 extern crate atty;
@@ -28,6 +23,16 @@ extern crate serde;
 #[macro_use] extern crate serde_derive;
 extern crate toml;
 extern crate regex;
+extern crate sha2;
+extern crate unicode_normalization;
+extern crate bcrypt;
+extern crate base64;
+extern crate md5;
+extern crate sha1;
+extern crate jsonwebtoken;
+extern crate hmac;
+#[cfg_attr(test, macro_use)] extern crate serde_json;
+#[cfg(test)] extern crate quickcheck;
 
 use futures_cpupool::CpuPool;
 use futures::{future::{Executor}, Future};
@@ -38,7 +43,8 @@ use tokio_core::reactor::Core;
 use tokio::net::TcpListener;
 use leak::Leak;
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 
 mod path;
 mod service;
@@ -47,6 +53,21 @@ mod compress;
 mod base36;
 mod negotiation;
 mod range;
+mod precondition;
+mod multipart;
+mod precompressed;
+mod lru;
+mod file_cache;
+mod etag;
+mod glob;
+mod redirect;
+mod netlify;
+mod watched;
+mod access;
+mod lookup;
+mod htpasswd;
+mod jwt;
+mod signed_url;
 #[macro_use] mod util;
 mod entity;
 mod mime;
@@ -54,16 +75,62 @@ mod config_print;
 mod config;
 
 pub use config::Config;
+pub use signed_url::parse_ttl;
 
 pub fn serve(config: Config) {
     env_logger::init();
 
-    use service::{log::Log, cors::Cors, root::Root, compress::Compress, browse::Browse, gate::Gate};
+    use service::{log::Log, cors::Cors, root::Root, compress::Compress, browse::Browse, gate::Gate, error_pages::ErrorPages, cache::Cache, headers::Headers, redirect::Redirect, auth::Auth, jwt::Jwt, signed_urls::SignedUrls, resolve::Resolve};
 
     let pool = Box::new(CpuPool::new(1)).leak();
 
     let config = Box::new(config).leak();
 
+    let file_cache = Box::new(config.file_cache.clone().map(file_cache::FileCache::new)).leak();
+
+    let etagger = Box::new(etag::ETagger::new(
+        config.etag.as_ref().map(|opts| opts.strategy).unwrap_or_default()
+    )).leak();
+
+    // Watches the root's _redirects and _headers files
+    let netlify = Box::new(netlify::Netlify::new(&config.server.root)).leak();
+
+    // [access] rules plus the root's .hunkignore
+    let access = Box::new(access::Access::new(&config.access, &config.server.root)).leak();
+
+    // [server] unicode_normalization and case_insensitive
+    let lookup = Box::new(lookup::Lookup::new(&config.server)).leak();
+
+    // [auth] users, re-read when the file changes
+    let htpasswd = Box::new(config.auth.as_ref().map(|auth| htpasswd::Htpasswd::new(auth.htpasswd.clone()))).leak();
+
+    // [jwt] keys. Refuse to start rather than serve without the check.
+    let verifier = Box::new(config.jwt.as_ref().map(|jwt| {
+        jwt::Verifier::new(jwt).unwrap_or_else(|e| {
+            eprintln!("[jwt] {}", e);
+            ::std::process::exit(1);
+        })
+    })).leak();
+
+    // [signed_urls] key, same deal
+    let signer = Box::new(config.signed_urls.as_ref().map(|signed_urls| {
+        signed_url::Signer::new(signed_urls).unwrap_or_else(|e| {
+            eprintln!("[signed_urls] {}", e);
+            ::std::process::exit(1);
+        })
+    })).leak();
+
+    // [error_pages] have to be files under the root
+    if let Some(ref error_pages) = config.error_pages {
+        if let Err(e) = error_pages.check(&config.server.root) {
+            eprintln!("[error_pages] {}", e);
+            ::std::process::exit(1);
+        }
+    }
+
+    // [auth], [jwt], and [signed_urls] judge the path that's actually served
+    let guarded = config.auth.is_some() || config.jwt.is_some() || config.signed_urls.is_some();
+
     // For Browse middleware.
     let root = Box::new(config.server.root.clone()).leak();
 
@@ -71,11 +138,19 @@ pub fn serve(config: Config) {
         // Request travels from bottom to top,
         // Response travels from top to bottom.
         pipe!(
-            Root::new(pool, &config.server),
-            (Browse::new[&config.browse, root.as_path()]),
+            Root::new(pool, &config.server, &config.compress, file_cache, etagger, access, lookup),
+            (Browse::new[&config.browse, &config.server, root.as_path(), access, lookup]),
+            (Auth::new[pool, &config.auth, htpasswd]),
+            (Jwt::new[&config.jwt, verifier]),
+            (SignedUrls::new[peer, &config.signed_urls, signer]),
+            (Resolve::new[pool, guarded, &config.server, &config.browse, access, lookup]),
+            (Redirect::new[pool, &config.redirect, &config.rewrite, &config.server, netlify, access, lookup]),
             (Cors::new[&config.cors]),
-            (Compress::new[pool, &config.gzip]),
+            (Cache::new[&config.cache]),
+            (Compress::new[pool, &config.compress]),
+            (ErrorPages::new[pool, &config.error_pages, &config.server]),
             (Log::new[peer, &config.log]),
+            (Headers::new[pool, &config.headers, netlify]),
             (Gate::new[])
         )
     };
@@ -94,7 +169,6 @@ pub fn serve(config: Config) {
             .map_err(|_e| {
                 // Note: Noisy (epipe)
                 // error!("http.serve_connection error: {:?}", _e);
-                ()
             });
 
         handle.execute(conn)
@@ -103,7 +177,7 @@ pub fn serve(config: Config) {
                 error!("handle.execute error: {:?}", e);
                 // TODO: Figure out how to handle this.
                 // For now, just unify with expected io::Error
-                std::io::Error::new(std::io::ErrorKind::Other, "error stub")
+                std::io::Error::other("error stub")
             })
     });
 
@@ -115,3 +189,30 @@ pub fn serve(config: Config) {
 
     core.run(server).unwrap();
 }
+
+// For `hunk sign`. req_path is given as it would appear in the url, and comes back
+// with a query string that's good for ttl.
+//
+// The signature is checked against the path that's actually served, so sign that,
+// e.g. /builds/App.zip for /BUILDS/app.zip under case_insensitive, or the target
+// of a [[rewrite]].
+pub fn sign_url(config: &Config, req_path: &str, ttl: Duration, ip: Option<IpAddr>) -> Result<String, String> {
+    let signer = match config.signed_urls {
+        None => return Err("the config has no [signed_urls] section".to_string()),
+        Some(ref signed_urls) => signed_url::Signer::new(signed_urls)?,
+    };
+
+    let req_path = if req_path.starts_with('/') { req_path.to_string() } else { format!("/{}", req_path) };
+    let access = access::Access::new(&config.access, &config.server.root);
+    let lookup = lookup::Lookup::new(&config.server);
+    let netlify = netlify::Netlify::new(&config.server.root);
+    let req_path = service::redirect::rewritten(&config.redirect, &config.rewrite, &config.server, &netlify, &access, &lookup, &req_path)
+        .unwrap_or(req_path);
+    let req_path = path::settle(&config.server, &req_path, &access, &lookup, config.browse.is_some()).unwrap_or(req_path);
+    let decoded = path::decode_path(&req_path).map_err(|_| format!("invalid path {:?}", req_path))?;
+    let expires = signed_url::now()
+        .checked_add(ttl.as_secs())
+        .ok_or_else(|| "the ttl is too long".to_string())?;
+
+    Ok(format!("{}?{}", path::encode_path(&decoded), signer.sign(&decoded, expires, ip)))
+}
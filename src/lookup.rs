@@ -0,0 +1,192 @@
+// Fallback for request paths that don't name a file on disk exactly, e.g. a browser
+// asking for /café.txt in NFC when macOS wrote café.txt to disk in NFD, or
+// /README.md when the file is readme.md.
+//
+// Each directory's entries are indexed by their folded name. The index is cached until
+// the directory's mtime changes, which happens whenever an entry is added, removed,
+// or renamed.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use unicode_normalization::UnicodeNormalization as Normalize;
+
+use config;
+
+// Forget every indexed directory once we're holding this many.
+const MAX_DIRS: usize = 1_024;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnicodeNormalization {
+    // Names must match byte for byte
+    #[default]
+    None,
+    // If there's no exact match, compare names in NFC
+    NfcFallback,
+}
+
+impl UnicodeNormalization {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            UnicodeNormalization::None => "none",
+            UnicodeNormalization::NfcFallback => "nfc-fallback",
+        }
+    }
+}
+
+struct Dir {
+    mtime: SystemTime,
+    // Folded name -> name on disk, or None if more than one entry folds to it
+    names: HashMap<String, Option<String>>,
+}
+
+#[derive(Default)]
+pub struct Lookup {
+    normalization: UnicodeNormalization,
+    case_insensitive: bool,
+    dirs: Mutex<HashMap<PathBuf, Dir>>,
+}
+
+impl fmt::Debug for Lookup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Lookup")
+            .field("normalization", &self.normalization)
+            .field("case_insensitive", &self.case_insensitive)
+            .finish()
+    }
+}
+
+impl Lookup {
+    pub fn new(config: &config::Server) -> Self {
+        Lookup {
+            normalization: config.unicode_normalization,
+            case_insensitive: config.case_insensitive,
+            dirs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.normalization != UnicodeNormalization::None || self.case_insensitive
+    }
+
+    fn fold(&self, name: &str) -> String {
+        let name = match self.normalization {
+            UnicodeNormalization::NfcFallback => name.nfc().collect::<String>(),
+            UnicodeNormalization::None => name.to_string(),
+        };
+        if self.case_insensitive {
+            name.to_lowercase()
+        } else {
+            name
+        }
+    }
+
+    // The names on disk for the decoded segments of a path under root, or None if
+    // any of them has no match. Segments that match exactly are kept as they are.
+    //
+    // A stat() per segment plus a read_dir() on a cache miss, so not on the reactor.
+    pub fn find(&self, root: &Path, segments: &[String]) -> Option<Vec<String>> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let mut dir = root.to_path_buf();
+        let mut found = Vec::with_capacity(segments.len());
+
+        for segment in segments {
+            let name = if fs::symlink_metadata(dir.join(segment)).is_ok() {
+                segment.clone()
+            } else {
+                self.find_in(&dir, segment)?
+            };
+            dir.push(&name);
+            found.push(name);
+        }
+
+        Some(found)
+    }
+
+    fn find_in(&self, dir: &Path, name: &str) -> Option<String> {
+        let mtime = fs::metadata(dir).and_then(|m| m.modified()).ok()?;
+        let folded = self.fold(name);
+
+        {
+            let dirs = self.dirs.lock().unwrap();
+            if let Some(cached) = dirs.get(dir).filter(|cached| cached.mtime == mtime) {
+                return cached.names.get(&folded).cloned().and_then(|name| name);
+            }
+        }
+
+        // Read the directory without holding the lock
+        let mut names = HashMap::new();
+        for entry in fs::read_dir(dir).ok()?.filter_map(|entry| entry.ok()) {
+            // Names that aren't UTF-8 can't be requested anyways
+            let entry_name = match entry.file_name().into_string() {
+                Ok(entry_name) => entry_name,
+                Err(_) => continue,
+            };
+            // Ambiguous, e.g. both README.md and readme.md under case_insensitive
+            names.entry(self.fold(&entry_name))
+                .and_modify(|existing| *existing = None)
+                .or_insert_with(|| Some(entry_name));
+        }
+
+        let found = names.get(&folded).cloned().and_then(|name| name);
+
+        let mut dirs = self.dirs.lock().unwrap();
+        if dirs.len() >= MAX_DIRS {
+            dirs.clear();
+        }
+        dirs.insert(dir.to_path_buf(), Dir { mtime, names });
+
+        found
+    }
+}
+
+#[test]
+fn test_find() {
+    let root = ::std::env::temp_dir().join(format!("hunk-test-lookup-{}", ::std::process::id()));
+    fs::create_dir_all(root.join("Docs")).unwrap();
+    fs::create_dir_all(root.join("both")).unwrap();
+    // "café" in NFD, as macOS would write it
+    fs::write(root.join("Docs/cafe\u{301}.txt"), "").unwrap();
+    fs::write(root.join("both/README.md"), "").unwrap();
+    fs::write(root.join("both/readme.md"), "").unwrap();
+
+    let segments = |path: &str| path.split('/').map(String::from).collect::<Vec<_>>();
+
+    let lookup = |normalization, case_insensitive| Lookup {
+        normalization,
+        case_insensitive,
+        dirs: Mutex::new(HashMap::new()),
+    };
+
+    let off = lookup(UnicodeNormalization::None, false);
+    let nfc = lookup(UnicodeNormalization::NfcFallback, false);
+    let nocase = lookup(UnicodeNormalization::None, true);
+    let both = lookup(UnicodeNormalization::NfcFallback, true);
+
+    assert_eq!(off.find(&root, &segments("Docs/caf\u{e9}.txt")), None);
+    assert_eq!(nfc.find(&root, &segments("Docs/caf\u{e9}.txt")), Some(segments("Docs/cafe\u{301}.txt")));
+    assert_eq!(nfc.find(&root, &segments("docs/caf\u{e9}.txt")), None);
+    assert_eq!(nocase.find(&root, &segments("docs/cafe\u{301}.TXT")), Some(segments("Docs/cafe\u{301}.txt")));
+    assert_eq!(both.find(&root, &segments("DOCS/CAF\u{c9}.txt")), Some(segments("Docs/cafe\u{301}.txt")));
+    // Exact matches win, and ambiguous ones are left alone
+    assert_eq!(nocase.find(&root, &segments("both/README.md")), Some(segments("both/README.md")));
+    assert_eq!(nocase.find(&root, &segments("both/Readme.md")), None);
+
+    // Picks up new entries once the directory changes
+    assert_eq!(nocase.find(&root, &segments("Docs/New.txt")), None);
+    fs::write(root.join("Docs/new.txt"), "").unwrap();
+    // Make sure the mtime moves even on filesystems with coarse timestamps
+    ::std::thread::sleep(::std::time::Duration::from_millis(1_100));
+    fs::write(root.join("Docs/other.txt"), "").unwrap();
+    assert_eq!(nocase.find(&root, &segments("Docs/New.txt")), Some(segments("Docs/new.txt")));
+
+    fs::remove_dir_all(&root).unwrap();
+}
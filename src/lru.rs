@@ -0,0 +1,89 @@
+// Least-recently-used map keyed by path, bounded by the total size of its entries.
+//
+// The caller says what an entry's size is, e.g. its length in bytes for the file
+// cache, or 1 to bound the number of entries.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+struct Slot<T> {
+    value: T,
+    size: u64,
+    tick: u64,
+}
+
+pub struct Lru<T> {
+    slots: HashMap<PathBuf, Slot<T>>,
+    // tick -> key, oldest first
+    order: BTreeMap<u64, PathBuf>,
+    tick: u64,
+    size: u64,
+}
+
+impl<T> Default for Lru<T> {
+    fn default() -> Self {
+        Lru { slots: HashMap::new(), order: BTreeMap::new(), tick: 0, size: 0 }
+    }
+}
+
+impl<T: Clone> Lru<T> {
+    // Marks the entry as just used.
+    pub fn touch(&mut self, path: &Path) -> Option<T> {
+        self.tick += 1;
+        let tick = self.tick;
+        let slot = self.slots.get_mut(path)?;
+        self.order.remove(&slot.tick);
+        self.order.insert(tick, path.to_path_buf());
+        slot.tick = tick;
+        Some(slot.value.clone())
+    }
+
+    pub fn remove(&mut self, path: &Path) {
+        if let Some(slot) = self.slots.remove(path) {
+            self.order.remove(&slot.tick);
+            self.size -= slot.size;
+        }
+    }
+
+    // Evicts the oldest entries until this one fits under max_size.
+    pub fn insert(&mut self, path: PathBuf, value: T, size: u64, max_size: u64) {
+        self.remove(&path);
+
+        while self.size + size > max_size {
+            let oldest = match self.order.keys().next() {
+                None => break,
+                Some(&tick) => self.order.remove(&tick).unwrap(),
+            };
+            self.remove(&oldest);
+        }
+
+        self.tick += 1;
+        self.size += size;
+        self.order.insert(self.tick, path.clone());
+        self.slots.insert(path, Slot { value, size, tick: self.tick });
+    }
+}
+
+#[test]
+fn test_evicts_least_recently_used() {
+    let mut lru = Lru::default();
+    lru.insert(PathBuf::from("a"), "a", 4, 10);
+    lru.insert(PathBuf::from("b"), "b", 4, 10);
+    // Touch "a" so that "b" is the oldest
+    assert!(lru.touch(Path::new("a")).is_some());
+    lru.insert(PathBuf::from("c"), "c", 4, 10);
+
+    assert!(lru.touch(Path::new("a")).is_some());
+    assert!(lru.touch(Path::new("b")).is_none());
+    assert!(lru.touch(Path::new("c")).is_some());
+    assert_eq!(lru.size, 8);
+
+    // Replacing an entry doesn't double count it
+    lru.insert(PathBuf::from("c"), "c", 2, 10);
+    assert_eq!(lru.size, 6);
+
+    // Entries bigger than the whole cache evict everything
+    lru.insert(PathBuf::from("d"), "d", 10, 10);
+    assert_eq!(lru.slots.len(), 1);
+    assert_eq!(lru.size, 10);
+}
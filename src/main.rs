@@ -17,6 +17,10 @@ fn read_config<P: AsRef<Path>>(path: P) -> Result<Config, String> {
 }
 
 fn main() {
+    if args().nth(1).as_deref() == Some("sign") {
+        return sign(args().skip(2).collect());
+    }
+
     // Parse first argv as path.
     // If given, then it must exist.
     let path = args()
@@ -50,3 +54,49 @@ fn main() {
 
     hunk::serve(config)
 }
+
+fn fail(message: String) -> ! {
+    eprintln!("hunk sign: {}", message);
+    ::std::process::exit(1)
+}
+
+// hunk sign <path> [--ttl 1h] [--ip <ip>] [--base <url>] [--config Hunk.toml]
+//
+// Prints a link to path that [signed_urls] lets through until the ttl runs out.
+fn sign(args: Vec<String>) {
+    let mut path = None;
+    let mut ttl = "1h".to_string();
+    let mut ip = None;
+    let mut base = None;
+    let mut config = PathBuf::from("Hunk.toml");
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            if path.is_some() {
+                fail(format!("unexpected argument {:?}", arg));
+            }
+            path = Some(arg);
+            continue;
+        }
+
+        let value = args.next().unwrap_or_else(|| fail(format!("{} needs a value", arg)));
+        match arg.as_str() {
+            "--ttl" => ttl = value,
+            "--ip" => ip = Some(value),
+            "--base" => base = Some(value),
+            "--config" => config = PathBuf::from(value),
+            _ => fail(format!("unknown option {}", arg)),
+        }
+    }
+
+    let path = path.unwrap_or_else(|| fail("usage: hunk sign <path> [--ttl 1h] [--ip <ip>] [--base <url>] [--config Hunk.toml]".to_string()));
+    let config = read_config(&config).unwrap_or_else(|e| fail(format!("failed to load {:?}: {}", config, e)));
+    let ttl = hunk::parse_ttl(&ttl).unwrap_or_else(|e| fail(e));
+    let ip = ip.map(|ip| ip.parse().unwrap_or_else(|_| fail(format!("invalid ip {:?}", ip))));
+
+    let url = hunk::sign_url(&config, &path, ttl, ip).unwrap_or_else(|e| fail(e));
+    let base = base.unwrap_or_else(|| format!("http://{}", config.server.addr));
+
+    println!("{}{}", base.trim_end_matches('/'), url);
+}
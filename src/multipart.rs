@@ -0,0 +1,88 @@
+// multipart/byteranges response bodies
+// <https://tools.ietf.org/html/rfc7233#appendix-A>
+
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::{stream, Stream};
+use hyper::{Body, Chunk};
+
+use base36;
+use entity::{ChunkStream, Entity};
+
+static BOUNDARY_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub struct ByteRanges {
+    boundary: String,
+    // (part headers, end-exclusive range)
+    parts: Vec<(String, Range<u64>)>,
+    closing: String,
+}
+
+impl ByteRanges {
+    // Expects end-inclusive ranges like the ones we get from range::parse_range_header.
+    pub fn new(entity: &Entity, ranges: &[Range<u64>]) -> Self {
+        let boundary = new_boundary();
+
+        let parts = ranges
+            .iter()
+            .map(|range| {
+                let head = format!(
+                    "--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary,
+                    entity.content_type().mime,
+                    range.start,
+                    range.end,
+                    entity.len()
+                );
+                (head, range.start..range.end + 1)
+            })
+            .collect();
+
+        let closing = format!("--{}--\r\n", boundary);
+
+        ByteRanges { boundary, parts, closing }
+    }
+
+    pub fn content_type(&self) -> String {
+        format!("multipart/byteranges; boundary={}", self.boundary)
+    }
+
+    // Exact length of the body so we never need chunked transfer-encoding.
+    pub fn len(&self) -> u64 {
+        let parts: u64 = self.parts
+            .iter()
+            // +2 for the CRLF after each part's payload
+            .map(|(head, range)| head.len() as u64 + (range.end - range.start) + 2)
+            .sum();
+
+        parts + self.closing.len() as u64
+    }
+
+    pub fn into_body(self, entity: &Entity, max_chunk_size: u64) -> Body {
+        let mut body: ChunkStream = Box::new(stream::empty());
+
+        for (head, range) in self.parts {
+            let part = stream::once(Ok(Chunk::from(head)))
+                .chain(entity.stream_range(range, max_chunk_size))
+                .chain(stream::once(Ok(Chunk::from("\r\n"))));
+            body = Box::new(body.chain(part));
+        }
+
+        let body = body.chain(stream::once(Ok(Chunk::from(self.closing))));
+
+        entity.spawn_body(Box::new(body))
+    }
+}
+
+// Boundary only needs to be absent from the payload, so a timestamp plus a
+// per-process counter is plenty.
+fn new_boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or(0);
+    let n = BOUNDARY_COUNTER.fetch_add(1, Ordering::Relaxed) as u64;
+    format!("hunk{}{}", base36::encode(nanos), base36::encode(n))
+}
@@ -1,10 +1,14 @@
 use hyper::header;
 
+use compress::Encoder;
+
 // If returns false, then there was an etag match so we should respond with not-modified.
-pub fn none_match(header_value: Option<&header::IfNoneMatch>, etag: &header::EntityTag) -> bool {
-    match header_value {
-        Some(&header::IfNoneMatch::Any) => false,
-        Some(&header::IfNoneMatch::Items(ref candidates)) => !(candidates as &[header::EntityTag])
+//
+// etag is None when ETags are disabled, in which case only "*" can match.
+pub fn none_match(header_value: Option<&header::IfNoneMatch>, etag: Option<&header::EntityTag>) -> bool {
+    match (header_value, etag) {
+        (Some(&header::IfNoneMatch::Any), _) => false,
+        (Some(header::IfNoneMatch::Items(candidates)), Some(etag)) => !(candidates as &[header::EntityTag])
             .iter()
             .any(|candidate| candidate.weak_eq(etag)),
         _ => true,
@@ -12,43 +16,130 @@ pub fn none_match(header_value: Option<&header::IfNoneMatch>, etag: &header::Ent
 }
 
 // if it returns false, then we send a precondition-failed response.
-pub fn any_match(header_value: Option<&header::IfMatch>, etag: &header::EntityTag) -> bool {
-    match header_value {
-        None | Some(&header::IfMatch::Any) => true,
-        Some(&header::IfMatch::Items(ref candidates)) => (candidates as &[header::EntityTag])
+pub fn any_match(header_value: Option<&header::IfMatch>, etag: Option<&header::EntityTag>) -> bool {
+    match (header_value, etag) {
+        (None, _) | (Some(&header::IfMatch::Any), _) => true,
+        (Some(header::IfMatch::Items(candidates)), Some(etag)) => (candidates as &[header::EntityTag])
             .iter()
             .any(|candidate| candidate.strong_eq(etag)),
+        (Some(&header::IfMatch::Items(_)), None) => false,
     }
 }
 
-// Returns Ok(Encoding) only if it's one of the compressions we support. Else we should not compress.
+#[derive(Debug, PartialEq)]
+pub struct NotAcceptable;
+
+// Picks the best encoder from our server-preferred list of available encoders.
+//
+// - Ok(Some(encoder)): compress with this encoder
+// - Ok(None): send the identity encoding
+// - Err(NotAcceptable): client excluded identity and everything we have, so 406
+//
+// <https://tools.ietf.org/html/rfc7231#section-5.3.4>
 pub fn negotiate_encoding(
     header_value: Option<&header::AcceptEncoding>,
-) -> Option<header::Encoding> {
-    match header_value {
-        None => None,
-        Some(&header::AcceptEncoding(ref qitems)) => {
-            // Note: qitems: &Vec<header::QualityItem<header::Encoding>>
-            let mut qitems = qitems.clone();
-
-            // Sort by client preference descending
-            qitems.sort_unstable_by_key(|qi| ::std::cmp::Reverse(qi.quality));
-
-            for qi in qitems {
-                match qi.item {
-                    header::Encoding::Gzip =>
-                        return Some(header::Encoding::Gzip),
-                    header::Encoding::Identity =>
-                        return None,
-                    header::Encoding::EncodingExt(ref ext) if ext == "*" =>
-                        // Use gzip if they have no preference
-                        return Some(header::Encoding::Gzip),
-                    _ =>
-                        {}
-                }
-            }
-
-            None
+    available: &[Encoder],
+) -> Result<Option<Encoder>, NotAcceptable> {
+    let qitems: &[header::QualityItem<header::Encoding>] = match header_value {
+        // No header means any encoding is acceptable, but we play it safe since
+        // plenty of clients that can't decode anything don't bother to send one.
+        None => return Ok(None),
+        Some(header::AcceptEncoding(qitems)) => qitems,
+    };
+
+    let zero = header::q(0u16);
+
+    let quality_of = |encoding: &header::Encoding| {
+        qitems.iter().find(|qi| qi.item == *encoding).map(|qi| qi.quality)
+    };
+
+    let star = quality_of(&header::Encoding::EncodingExt("*".to_string()));
+
+    // Best encoder by client preference, ties broken by our order in `available`.
+    // An encoder the client didn't mention falls back to its "*" preference, else it's unacceptable.
+    let mut best: Option<(Encoder, header::Quality)> = None;
+    for encoder in available {
+        let quality = qitems.iter()
+            .find(|qi| encoder.matches(&qi.item))
+            .map(|qi| qi.quality)
+            .or(star)
+            .unwrap_or(zero);
+
+        if quality == zero {
+            continue;
         }
+
+        match best {
+            Some((_, best_quality)) if best_quality >= quality =>
+                {},
+            _ =>
+                best = Some((*encoder, quality)),
+        }
+    }
+
+    // Identity is always acceptable unless it's explicitly excluded by
+    // "identity;q=0" or by "*;q=0" without an identity entry.
+    let identity = quality_of(&header::Encoding::Identity).or(star);
+
+    match (best, identity) {
+        // Client explicitly prefers identity
+        (Some((_, quality)), Some(identity)) if identity > quality =>
+            Ok(None),
+        (Some((encoder, _)), _) =>
+            Ok(Some(encoder)),
+        (None, Some(identity)) if identity == zero =>
+            Err(NotAcceptable),
+        (None, _) =>
+            Ok(None),
+    }
+}
+
+#[test]
+#[allow(clippy::type_complexity)]
+fn test_negotiate_encoding() {
+    use self::Encoder::*;
+
+    fn accept(s: &'static str) -> header::AcceptEncoding {
+        header::Header::parse_header(&s.into()).unwrap()
+    }
+
+    let all: &[Encoder] = &[Brotli, Zstd, Gzip, Deflate];
+
+    // (Accept-Encoding, available, expected)
+    let table: Vec<(Option<&str>, &[Encoder], Result<Option<Encoder>, NotAcceptable>)> = vec![
+        (None, all, Ok(None)),
+        (Some(""), all, Ok(None)),
+        (Some("gzip"), all, Ok(Some(Gzip))),
+        (Some("gzip, deflate, br"), all, Ok(Some(Brotli))),
+        (Some("gzip, deflate, br, zstd"), all, Ok(Some(Brotli))),
+        (Some("gzip, deflate, br, zstd"), &[Zstd, Gzip][..], Ok(Some(Zstd))),
+        // Client preference beats server preference
+        (Some("br;q=0.5, gzip"), all, Ok(Some(Gzip))),
+        (Some("br;q=0.5, gzip;q=0.5"), all, Ok(Some(Brotli))),
+        // q=0 means "not acceptable"
+        (Some("gzip;q=0"), all, Ok(None)),
+        (Some("gzip;q=0, deflate"), all, Ok(Some(Deflate))),
+        (Some("*"), all, Ok(Some(Brotli))),
+        (Some("*, br;q=0"), all, Ok(Some(Zstd))),
+        (Some("*;q=0.5, gzip"), all, Ok(Some(Gzip))),
+        (Some("gzip"), &[Brotli][..], Ok(None)),
+        (Some("identity"), all, Ok(None)),
+        // Client prefers identity
+        (Some("gzip;q=0.5, identity"), all, Ok(None)),
+        // Identity excluded
+        (Some("identity;q=0"), all, Err(NotAcceptable)),
+        (Some("*;q=0"), all, Err(NotAcceptable)),
+        (Some("*;q=0, identity"), all, Ok(None)),
+        (Some("gzip, identity;q=0"), all, Ok(Some(Gzip))),
+        (Some("gzip, identity;q=0"), &[Brotli][..], Err(NotAcceptable)),
+    ];
+
+    for (header_value, available, expected) in table {
+        let header_value = header_value.map(accept);
+        assert_eq!(
+            expected,
+            negotiate_encoding(header_value.as_ref(), available),
+            "{:?} {:?}", header_value, available
+        );
     }
 }
@@ -0,0 +1,292 @@
+// Netlify's `_redirects` and `_headers` files at the root of the site.
+//
+// <https://www.netlify.com/docs/redirects/>
+// <https://www.netlify.com/docs/headers-and-basic-auth/>
+//
+// Both are re-read whenever they change on disk.
+// They're config, not content, so they're never served or listed themselves.
+
+use std::fmt;
+use std::path::Path;
+
+use hyper::StatusCode;
+
+use config;
+use redirect::{Pattern, Rule};
+use watched::Watched;
+
+pub const REDIRECTS_FILE: &str = "_redirects";
+pub const HEADERS_FILE: &str = "_headers";
+
+// name is a file name at the root. Case doesn't matter since a case-insensitive
+// filesystem, or [server] case_insensitive, serves _REDIRECTS from _redirects.
+pub fn is_config_file(name: &str) -> bool {
+    name.eq_ignore_ascii_case(REDIRECTS_FILE) || name.eq_ignore_ascii_case(HEADERS_FILE)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Redirect(StatusCode),
+    // 200: serve `to` in place of the requested path
+    Rewrite,
+    // 404: serve `to` with a 404 status, e.g. a custom not-found page for a section
+    NotFound,
+}
+
+#[derive(Debug, Clone)]
+pub struct Redirect {
+    pub rule: Rule,
+    pub action: Action,
+    // Unforced rules are shadowed by a file that exists at the requested path.
+    pub force: bool,
+}
+
+// e.g. `/store id=:id  /blog/:id  301!`
+pub fn parse_redirects(text: &str) -> Vec<Redirect> {
+    let mut redirects = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_redirect(line) {
+            Ok(redirect) =>
+                redirects.push(redirect),
+            Err(e) =>
+                warn!("{} line {}: {}", REDIRECTS_FILE, i + 1, e),
+        }
+    }
+    redirects
+}
+
+fn parse_redirect(line: &str) -> Result<Redirect, String> {
+    let mut tokens = line.split_whitespace().peekable();
+
+    let from = tokens.next().ok_or("missing from")?;
+
+    // Query params come between from and to
+    let mut query = Vec::new();
+    while let Some(token) = tokens.peek().cloned() {
+        if token.starts_with('/') || token.contains("://") {
+            break;
+        }
+        let mut parts = token.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if !key.is_empty() =>
+                query.push((key.to_string(), value.to_string())),
+            _ =>
+                return Err(format!("unexpected {:?}", token)),
+        }
+        tokens.next();
+    }
+
+    let to = tokens.next().ok_or("missing to")?;
+
+    let (status, force) = match tokens.next() {
+        None => (301, false),
+        Some(token) => {
+            let force = token.ends_with('!');
+            let status = token.trim_end_matches('!')
+                .parse::<u16>()
+                .map_err(|_| format!("invalid status {:?}", token))?;
+            (status, force)
+        }
+    };
+
+    // Country=, Language=, Role= conditions
+    if let Some(token) = tokens.next() {
+        return Err(format!("unsupported condition {:?}", token));
+    }
+
+    let action = match status {
+        200 => Action::Rewrite,
+        404 => Action::NotFound,
+        301 => Action::Redirect(StatusCode::MovedPermanently),
+        302 => Action::Redirect(StatusCode::Found),
+        303 => Action::Redirect(StatusCode::SeeOther),
+        307 => Action::Redirect(StatusCode::TemporaryRedirect),
+        308 => Action::Redirect(StatusCode::PermanentRedirect),
+        _ => return Err(format!("unsupported status {}", status)),
+    };
+
+    // We don't proxy, so rewrites have to stay on this site
+    match action {
+        Action::Redirect(_) => {},
+        _ if to.starts_with('/') => {},
+        _ => return Err(format!("cannot proxy to {:?}", to)),
+    }
+
+    let rule = Rule::new(from, query, to.to_string())?;
+
+    Ok(Redirect { rule, action, force })
+}
+
+#[derive(Debug, Clone)]
+pub struct HeaderBlock {
+    pub pattern: Pattern,
+    pub headers: Vec<(String, String)>,
+}
+
+// e.g.
+//
+//     /assets/*
+//       Cache-Control: public, max-age=31536000
+//       X-Frame-Options: DENY
+//
+// Repeated names within a block are joined with commas.
+pub fn parse_headers(text: &str) -> Vec<HeaderBlock> {
+    let mut blocks: Vec<HeaderBlock> = Vec::new();
+    // Skips the headers of a block whose path we couldn't parse
+    let mut skipping = false;
+
+    for (i, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        let indented = line.starts_with(' ') || line.starts_with('\t');
+
+        if !indented {
+            match Pattern::new(trimmed) {
+                Ok(pattern) => {
+                    blocks.push(HeaderBlock { pattern, headers: Vec::new() });
+                    skipping = false;
+                }
+                Err(e) => {
+                    warn!("{} line {}: {}", HEADERS_FILE, i + 1, e);
+                    skipping = true;
+                }
+            }
+            continue;
+        }
+
+        if skipping {
+            continue;
+        }
+
+        let block = match blocks.last_mut() {
+            None => {
+                warn!("{} line {}: header without a path", HEADERS_FILE, i + 1);
+                continue;
+            }
+            Some(block) => block,
+        };
+
+        let mut parts = trimmed.splitn(2, ':');
+        let (name, value) = match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) if !name.trim().is_empty() =>
+                (name.trim(), value.trim()),
+            _ => {
+                warn!("{} line {}: expected \"Name: value\"", HEADERS_FILE, i + 1);
+                continue;
+            }
+        };
+
+        if !config::is_header_name(name) {
+            warn!("{} line {}: {:?} isn't a valid header name", HEADERS_FILE, i + 1, name);
+            continue;
+        }
+        if !config::is_header_value(value) {
+            warn!("{} line {}: header value has a line break", HEADERS_FILE, i + 1);
+            continue;
+        }
+
+        match block.headers.iter_mut().find(|&&mut (ref n, _)| n.eq_ignore_ascii_case(name)) {
+            Some(&mut (_, ref mut existing)) => {
+                existing.push_str(", ");
+                existing.push_str(value);
+            }
+            None =>
+                block.headers.push((name.to_string(), value.to_string())),
+        }
+    }
+
+    blocks
+}
+
+pub struct Netlify {
+    pub redirects: Watched<Vec<Redirect>>,
+    pub headers: Watched<Vec<HeaderBlock>>,
+}
+
+impl fmt::Debug for Netlify {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Netlify({:?}, {:?})", self.redirects.path(), self.headers.path())
+    }
+}
+
+impl Netlify {
+    pub fn new(root: &Path) -> Self {
+        Netlify {
+            redirects: Watched::new(root.join(REDIRECTS_FILE), parse_redirects),
+            headers: Watched::new(root.join(HEADERS_FILE), parse_headers),
+        }
+    }
+}
+
+#[test]
+fn test_parse_redirects() {
+    let redirects = parse_redirects("
+        # comment
+        /home              /
+        /blog/:slug        /posts/:slug      302
+        /news/*            /blog/:splat      301!
+        /store id=:id      /products/:id
+        /app/*             /app/index.html   200
+        /docs/*            /docs/404.html    404
+        /cn/*              /zh/:splat        302  Country=cn
+        /api/*             https://api.example.com/:splat  200
+        /bad               /x                418
+    ");
+
+    let summary = redirects.iter()
+        .map(|r| (r.rule.from.as_str(), r.rule.to.as_str(), r.action.clone(), r.force))
+        .collect::<Vec<_>>();
+
+    assert_eq!(summary, vec![
+        ("/home", "/", Action::Redirect(StatusCode::MovedPermanently), false),
+        ("/blog/:slug", "/posts/:slug", Action::Redirect(StatusCode::Found), false),
+        ("/news/*", "/blog/:splat", Action::Redirect(StatusCode::MovedPermanently), true),
+        ("/store", "/products/:id", Action::Redirect(StatusCode::MovedPermanently), false),
+        ("/app/*", "/app/index.html", Action::Rewrite, false),
+        ("/docs/*", "/docs/404.html", Action::NotFound, false),
+    ]);
+
+    assert_eq!(redirects[3].rule.apply("/store", Some("id=7")), Some("/products/7".to_string()));
+}
+
+#[test]
+fn test_parse_headers() {
+    let blocks = parse_headers("
+/*
+  X-Frame-Options: DENY
+  Link: </a.css>; rel=preload
+  Link: </b.js>; rel=preload
+# comment
+/assets/*
+  Cache-Control: public, max-age=31536000
+  broken line
+  Bad Name: x
+  X-Split: a\rInjected: b
+");
+
+    assert_eq!(blocks.len(), 2);
+    assert_eq!(blocks[0].pattern.as_str(), "/*");
+    assert_eq!(blocks[0].headers, vec![
+        ("X-Frame-Options".to_string(), "DENY".to_string()),
+        ("Link".to_string(), "</a.css>; rel=preload, </b.js>; rel=preload".to_string()),
+    ]);
+    assert_eq!(blocks[1].headers, vec![
+        ("Cache-Control".to_string(), "public, max-age=31536000".to_string()),
+    ]);
+    assert!(blocks[1].pattern.is_match("/assets/app.js"));
+}
+
+#[test]
+fn test_is_config_file() {
+    assert!(is_config_file("_redirects"));
+    assert!(is_config_file("_headers"));
+    assert!(is_config_file("_REDIRECTS"));
+    assert!(!is_config_file("_redirects.txt"));
+}
@@ -1,20 +1,18 @@
+use std::fs;
 use std::path::{Path, PathBuf};
-use std::str::Utf8Error;
 
 use percent_encoding as pe;
 
-fn percent_decode_str(s: &str) -> Result<String, Utf8Error> {
-    pe::percent_decode(s.as_bytes()).decode_utf8().map(|x| x.to_string())
-}
+use access::Access;
+use config;
+use lookup::Lookup;
+use netlify;
 
 // A path is safe if it doesn't try to /./ or /../
 fn is_safe_path(path: &Path) -> bool {
     use std::path::Component::{Normal, RootDir};
 
-    path.components().all(|c| match c {
-        RootDir | Normal(_) => true,
-        _ => false,
-    })
+    path.components().all(|c| matches!(c, RootDir | Normal(_)))
 }
 
 #[test]
@@ -26,33 +24,694 @@ fn test_is_safe_path() {
     //    assert!(!is_safe_path(Path::new("/a/./c"))); NOTE: . gets dropped here?
 }
 
+// What to do with requests for dotfiles like /.env or /.git/config, modeled on serve-static.
+// <https://github.com/expressjs/serve-static#dotfiles>
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Dotfiles {
+    Allow,
+    // 403
+    Deny,
+    // 404, as if they weren't there
+    #[default]
+    Ignore,
+}
+
+impl Dotfiles {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Dotfiles::Allow => "allow",
+            Dotfiles::Deny => "deny",
+            Dotfiles::Ignore => "ignore",
+        }
+    }
+
+    // Whether a file or folder name is visible under this policy.
+    // .well-known is always allowed since ACME challenges and the like live there.
+    // <https://tools.ietf.org/html/rfc5785>
+    pub fn allows(&self, name: &str) -> bool {
+        *self == Dotfiles::Allow || !name.starts_with('.') || name == ".well-known"
+    }
+}
+
+// What to do when a request path runs through a symlink.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Symlinks {
+    // Follow them anywhere, e.g. into a shared assets folder outside the root
+    Follow,
+    // Follow them only if they land inside the root, else 404
+    #[default]
+    WithinRoot,
+    // 403 for any path that runs through a symlink
+    Deny,
+}
+
+impl Symlinks {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Symlinks::Follow => "follow",
+            Symlinks::WithinRoot => "within-root",
+            Symlinks::Deny => "deny",
+        }
+    }
+}
+
+// is_safe_path only looks at the path's text, but File::open resolves symlinks,
+// so a link inside the root can still point at /etc. This checks where the path
+// actually lands against the (already canonical) root.
+//
+// Paths that don't exist pass since there's nothing to serve anyways.
+//
+// Canonicalizing or walking the components stats each one, so it's pool work.
+pub fn check_symlinks(root: &Path, entity_path: &Path, symlinks: Symlinks) -> Result<(), PathError> {
+    match symlinks {
+        Symlinks::Follow =>
+            Ok(()),
+        Symlinks::WithinRoot =>
+            match entity_path.canonicalize() {
+                Ok(ref target) if !target.starts_with(root) =>
+                    Err(PathError::NotFound),
+                _ =>
+                    Ok(()),
+            },
+        Symlinks::Deny => {
+            let relative = entity_path.strip_prefix(root).map_err(|_| PathError::NotFound)?;
+            let mut path = root.to_path_buf();
+            for component in relative.components() {
+                path.push(component);
+                match fs::symlink_metadata(&path) {
+                    Ok(ref m) if m.file_type().is_symlink() =>
+                        return Err(PathError::Forbidden),
+                    Ok(_) =>
+                        {},
+                    Err(_) =>
+                        return Ok(()),
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PathError {
+    // Not a path we could ever serve, e.g. it has an encoded slash, a NUL byte, or a "..". 400.
+    Malformed,
+    // Nothing we'd serve there, e.g. it's missing, hidden, or an ignored dotfile. 404.
+    NotFound,
+    // A dotfile under `dotfiles = "deny"` or a symlink under `symlinks = "deny"`. 403.
+    Forbidden,
+}
+
+// A request path decoded one segment at a time.
+//
+// Decoding the whole path at once would turn "%2F" into a real separator, so
+// segments are split on the raw "/" first and each is decoded on its own.
+// Empty and "." segments are dropped, so "/a//./b" is the same path as "/a/b".
+#[derive(Debug, PartialEq)]
+pub struct RequestPath {
+    // Still percent-encoded but collapsed, e.g. "/a%20b/c/" for "//a%20b/./c/"
+    pub canonical: String,
+    // Decoded, e.g. ["a b", "c"]
+    pub segments: Vec<String>,
+}
+
+impl RequestPath {
+    pub fn parse(req_path: &str) -> Result<RequestPath, PathError> {
+        // request path must be absolute
+        if !req_path.starts_with('/') {
+            return Err(PathError::Malformed);
+        }
+
+        let mut canonical = String::with_capacity(req_path.len());
+        let mut segments = Vec::new();
+
+        for raw in req_path[1..].split('/') {
+            // e.g. GET "/%E4%B8%AD%E6%96%87.txt" should hit "/中文.txt"
+            let segment = pe::percent_decode(raw.as_bytes())
+                .decode_utf8()
+                .map_err(|_| PathError::Malformed)?;
+
+            match segment.as_ref() {
+                "" | "." =>
+                    continue,
+                // Security: request path cannot climb directories
+                ".." =>
+                    return Err(PathError::Malformed),
+                _ =>
+                    {},
+            }
+
+            // "/" and "\" would be separators once they hit the filesystem, and
+            // control characters (NUL especially) have no business in a file name.
+            if segment.chars().any(|c| c == '/' || c == '\\' || c.is_control()) {
+                return Err(PathError::Malformed);
+            }
+
+            canonical.push('/');
+            canonical.push_str(raw);
+            segments.push(segment.into_owned());
+        }
+
+        // Keep the trailing slash that tells a directory listing from a redirect to one
+        if segments.is_empty() || req_path.ends_with('/') || req_path.ends_with("/.") {
+            canonical.push('/');
+        }
+
+        Ok(RequestPath { canonical, segments })
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.canonical.ends_with('/')
+    }
+
+    // e.g. "/a b/c/"
+    pub fn decoded(&self) -> String {
+        let mut decoded = String::new();
+        for segment in &self.segments {
+            decoded.push('/');
+            decoded.push_str(segment);
+        }
+        if self.is_dir() {
+            decoded.push('/');
+        }
+        decoded
+    }
+}
+
+// The decoded form of a request path, for matching it against globs and rules.
+pub fn decode_path(req_path: &str) -> Result<String, PathError> {
+    RequestPath::parse(req_path).map(|path| path.decoded())
+}
+
+// The inverse of decode_path, e.g. for linking to "/a b/100%.txt" from a listing.
+pub fn encode_path(decoded: &str) -> String {
+    decoded.split('/')
+        .map(|segment| pe::utf8_percent_encode(segment, pe::PATH_SEGMENT_ENCODE_SET).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
 // Join root with request path to get the asset path candidate.
-pub fn get_entity_path(root: &Path, req_path: &str) -> Option<PathBuf> {
-    // request path must be absolute
-    if !req_path.starts_with('/') {
-        return None;
+pub fn get_entity_path(root: &Path, req_path: &str, dotfiles: Dotfiles, access: &Access) -> Result<PathBuf, PathError> {
+    let path = RequestPath::parse(req_path)?;
+    join(root, &path, dotfiles, access)
+}
+
+fn join(root: &Path, path: &RequestPath, dotfiles: Dotfiles, access: &Access) -> Result<PathBuf, PathError> {
+    // Every segment is checked, so /.git/config is as hidden as /.git
+    if path.segments.iter().any(|segment| !dotfiles.allows(segment)) {
+        return match dotfiles {
+            Dotfiles::Deny => Err(PathError::Forbidden),
+            _ => Err(PathError::NotFound),
+        };
     }
 
-    // Percent-decode the request uri, e.g. GET "/%E4%B8%AD%E6%96%87.txt" should hit "/中文.txt"
-    let req_path = percent_decode_str(req_path).ok()?;
+    // Netlify's _redirects and _headers are config, not content
+    if path.segments.len() == 1 && netlify::is_config_file(&path.segments[0]) {
+        return Err(PathError::NotFound);
+    }
 
-    // Security: request path cannot climb directories
-    if !is_safe_path(Path::new(&req_path)) {
-        return None;
-    };
+    // [access] and .hunkignore
+    if !access.allows(&path.decoded()) {
+        return Err(PathError::NotFound);
+    }
 
     let mut final_path = root.to_path_buf();
-    final_path.push(&req_path[1..]);
+    for segment in &path.segments {
+        // Belt and braces: a segment is a single normal component by now
+        if !is_safe_path(Path::new(segment)) {
+            return Err(PathError::Malformed);
+        }
+        final_path.push(segment);
+    }
+
+    Ok(final_path)
+}
+
+// join, but when nothing's there by that exact name, falls back to the names that
+// [server] unicode_normalization and case_insensitive would match instead.
+// The names on disk go through the dotfiles and access checks all over again
+// so that e.g. /SECRET/ can't sneak past a deny glob for /secret/.
+//
+// Returns the path as it's named on disk along with where that is.
+fn locate(root: &Path, path: RequestPath, dotfiles: Dotfiles, access: &Access, lookup: &Lookup) -> Result<(RequestPath, PathBuf), PathError> {
+    let entity_path = join(root, &path, dotfiles, access)?;
+
+    if !lookup.is_enabled() || fs::symlink_metadata(&entity_path).is_ok() {
+        return Ok((path, entity_path));
+    }
+
+    match lookup.find(root, &path.segments) {
+        None =>
+            Ok((path, entity_path)),
+        Some(segments) => {
+            let mut canonical = encode_path(&format!("/{}", segments.join("/")));
+            if path.is_dir() {
+                canonical.push('/');
+            }
+            let found = RequestPath { canonical, segments };
+            let entity_path = join(root, &found, dotfiles, access)?;
+            Ok((found, entity_path))
+        }
+    }
+}
+
+#[test]
+fn test_request_path() {
+    // (request path, expected)
+    let table = vec![
+        ("/", Ok(("/", Vec::<&str>::new()))),
+        ("//", Ok(("/", vec![]))),
+        ("/a/b", Ok(("/a/b", vec!["a", "b"]))),
+        ("/a/b/", Ok(("/a/b/", vec!["a", "b"]))),
+        ("//a///b", Ok(("/a/b", vec!["a", "b"]))),
+        ("/a/./b/.", Ok(("/a/b/", vec!["a", "b"]))),
+        ("/a/%2E/b", Ok(("/a/b", vec!["a", "b"]))),
+        ("/a%20b/%E4%B8%AD", Ok(("/a%20b/%E4%B8%AD", vec!["a b", "中"]))),
+        ("a", Err(&PathError::Malformed)),
+        ("/../a", Err(&PathError::Malformed)),
+        ("/a/%2E%2E/b", Err(&PathError::Malformed)),
+        ("/a%2Fb", Err(&PathError::Malformed)),
+        ("/a%2fb", Err(&PathError::Malformed)),
+        ("/a%5Cb", Err(&PathError::Malformed)),
+        ("/a\\b", Err(&PathError::Malformed)),
+        ("/a%00.txt", Err(&PathError::Malformed)),
+        ("/a%0A", Err(&PathError::Malformed)),
+        ("/a%7F", Err(&PathError::Malformed)),
+        ("/%FF", Err(&PathError::Malformed)),
+    ];
 
-    Some(final_path)
+    for (req_path, expected) in table {
+        let actual = RequestPath::parse(req_path);
+        let actual = actual.as_ref().map(|path| (path.canonical.as_str(), path.segments.iter().map(|s| s.as_str()).collect()));
+        assert_eq!(expected, actual, "{:?}", req_path);
+    }
+}
+
+#[test]
+fn test_encode_path() {
+    assert_eq!(encode_path("/"), "/");
+    assert_eq!(encode_path("/a b/100%.txt"), "/a%20b/100%25.txt");
+    assert_eq!(decode_path(&encode_path("/docs/中文/")), Ok("/docs/中文/".to_string()));
 }
 
 #[test]
 fn test_get_entity_path() {
-    assert_eq!(get_entity_path(Path::new("foo"), "/"), Some(PathBuf::from("foo")));
-    assert_eq!(get_entity_path(Path::new("foo"), "/bar"), Some(PathBuf::from("foo/bar")));
-    assert_eq!(get_entity_path(Path::new("foo"), "/../bar"), None);
-    assert_eq!(get_entity_path(Path::new("foo"), "bar"), None);
-    assert_eq!(get_entity_path(Path::new("foo"), "/folder/"), Some(PathBuf::from("foo/folder/")));
-    assert_eq!(get_entity_path(Path::new("."), "/%E4%B8%AD%E6%96%87.txt"), Some(PathBuf::from("./中文.txt")));
-}
\ No newline at end of file
+    use self::Dotfiles::*;
+
+    assert_eq!(get_entity_path(Path::new("foo"), "/", Ignore, &Access::default()), Ok(PathBuf::from("foo")));
+    assert_eq!(get_entity_path(Path::new("foo"), "/bar", Ignore, &Access::default()), Ok(PathBuf::from("foo/bar")));
+    assert_eq!(get_entity_path(Path::new("foo"), "/../bar", Ignore, &Access::default()), Err(PathError::Malformed));
+    assert_eq!(get_entity_path(Path::new("foo"), "bar", Ignore, &Access::default()), Err(PathError::Malformed));
+    assert_eq!(get_entity_path(Path::new("foo"), "/folder/", Ignore, &Access::default()), Ok(PathBuf::from("foo/folder/")));
+    assert_eq!(get_entity_path(Path::new("foo"), "//a/./b", Ignore, &Access::default()), Ok(PathBuf::from("foo/a/b")));
+    assert_eq!(get_entity_path(Path::new("foo"), "/a%2F..%2F..%2Fetc", Ignore, &Access::default()), Err(PathError::Malformed));
+    assert_eq!(get_entity_path(Path::new("."), "/%E4%B8%AD%E6%96%87.txt", Ignore, &Access::default()), Ok(PathBuf::from("./中文.txt")));
+}
+
+#[test]
+fn test_get_entity_path_dotfiles() {
+    use self::Dotfiles::*;
+
+    let root = Path::new("foo");
+
+    // (request path, policy, expected)
+    let table = vec![
+        ("/.env", Allow, Ok(root.join(".env"))),
+        ("/.env", Ignore, Err(PathError::NotFound)),
+        ("/.env", Deny, Err(PathError::Forbidden)),
+        ("/.git/config", Ignore, Err(PathError::NotFound)),
+        ("/.git/config", Deny, Err(PathError::Forbidden)),
+        ("/a/.git/config", Deny, Err(PathError::Forbidden)),
+        ("/%2Egit/config", Deny, Err(PathError::Forbidden)),
+        ("/a/b/.hidden", Ignore, Err(PathError::NotFound)),
+        ("/.well-known/acme-challenge/abc", Deny, Ok(root.join(".well-known/acme-challenge/abc"))),
+        ("/.well-known/.secret", Deny, Err(PathError::Forbidden)),
+        ("/a.b/c.d", Deny, Ok(root.join("a.b/c.d"))),
+        ("/_redirects", Allow, Err(PathError::NotFound)),
+        ("/_HEADERS/", Allow, Err(PathError::NotFound)),
+        ("/docs/_redirects", Allow, Ok(root.join("docs/_redirects"))),
+    ];
+
+    for (req_path, dotfiles, expected) in table {
+        assert_eq!(expected, get_entity_path(root, req_path, dotfiles, &Access::default()), "{:?} {:?}", req_path, dotfiles);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Resolved {
+    // Regular file to serve, possibly a directory's index file
+    File(PathBuf),
+    // Directory with none of the configured index files
+    Directory(PathBuf),
+    // Directory requested without its trailing slash. Holds the location to redirect to
+    // so that relative links in its index resolve against the directory.
+    Redirect(String),
+}
+
+// Resolves a request path to what's on disk, or why there's nothing to serve.
+// Blocks on stat(), plus a read_dir() per segment under case_insensitive.
+pub fn resolve(
+    server: &config::Server,
+    req_path: &str,
+    query: Option<&str>,
+    access: &Access,
+    lookup: &Lookup,
+) -> Result<Resolved, PathError> {
+    let root = &server.root;
+    let dotfiles = server.dotfiles;
+    let symlinks = server.symlinks;
+
+    let (path, entity_path) = locate(root, RequestPath::parse(req_path)?, dotfiles, access, lookup)?;
+    check_symlinks(root, &entity_path, symlinks)?;
+    let metadata = fs::metadata(&entity_path).map_err(|_| PathError::NotFound)?;
+
+    if metadata.is_file() {
+        return Ok(Resolved::File(entity_path));
+    }
+
+    if !metadata.is_dir() {
+        return Err(PathError::NotFound);
+    }
+
+    // Built from the canonical path so that e.g. "//evil.example/" can't become
+    // a protocol-relative Location. Names found by the lookup fallback redirect
+    // to how they're named on disk.
+    if !path.is_dir() {
+        let location = match query {
+            Some(query) => format!("{}/?{}", path.canonical, query),
+            None => format!("{}/", path.canonical),
+        };
+        return Ok(Resolved::Redirect(location));
+    }
+
+    let decoded = path.decoded();
+    let index_path = server.index
+        .iter()
+        .filter(|filename| dotfiles.allows(filename))
+        .filter(|filename| access.allows(&format!("{}{}", decoded, filename)))
+        .map(|filename| entity_path.join(filename))
+        .find(|path| fs::metadata(path).map(|m| m.is_file()).unwrap_or(false)
+            && check_symlinks(root, path, symlinks).is_ok());
+
+    match index_path {
+        Some(path) => Ok(Resolved::File(path)),
+        None => Ok(Resolved::Directory(entity_path)),
+    }
+}
+
+// nginx-style try_files: the first candidate that's a regular file wins.
+// "$uri" in a candidate is replaced with the request path.
+//
+// With skip_assets, paths that have a file extension never fall back so that
+// e.g. a missing /app.js still 404s instead of getting the SPA's index.html.
+pub fn try_files(server: &config::Server, req_path: &str, access: &Access, lookup: &Lookup) -> Option<PathBuf> {
+    // Don't paper over a malformed path with a fallback
+    RequestPath::parse(req_path).ok()?;
+
+    if server.try_files_skip_assets && Path::new(req_path).extension().is_some() {
+        return None;
+    }
+
+    server.try_files
+        .iter()
+        .map(|candidate| candidate.replace("$uri", req_path.trim_end_matches('/')))
+        .filter_map(|candidate| RequestPath::parse(&candidate).ok())
+        .filter_map(|candidate| locate(&server.root, candidate, server.dotfiles, access, lookup).ok())
+        .map(|(_, path)| path)
+        .find(|path| fs::metadata(path).map(|m| m.is_file()).unwrap_or(false)
+            && check_symlinks(&server.root, path, server.symlinks).is_ok())
+}
+
+// The request path of what Browse and Root would serve for req_path, spelled the way
+// it's named on disk, so that path policies like [auth] judge the file itself rather
+// than whatever the client typed:
+//
+// - the names found by the lookup fallback, e.g. /Photos/a.jpg for /photos/A.JPG
+// - a directory's index file, e.g. /docs/index.html for /docs/
+// - the try_files candidate when there's nothing at the path
+//
+// None when nothing would be served, only a redirect or an error. Blocks like resolve.
+pub fn settle(server: &config::Server, req_path: &str, access: &Access, lookup: &Lookup, browse: bool) -> Option<String> {
+    let (entity_path, is_dir) = match resolve(server, req_path, None, access, lookup) {
+        Ok(Resolved::File(path)) => (path, false),
+        Ok(Resolved::Directory(ref path)) if browse => (path.clone(), true),
+        Ok(Resolved::Directory(_)) | Err(PathError::NotFound) => (try_files(server, req_path, access, lookup)?, false),
+        Ok(Resolved::Redirect(_)) | Err(_) => return None,
+    };
+
+    let relative = entity_path.strip_prefix(&server.root).ok()?;
+    let segments = relative.components()
+        .map(|component| component.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()?;
+
+    let mut settled = encode_path(&format!("/{}", segments.join("/")));
+    if is_dir && !settled.ends_with('/') {
+        settled.push('/');
+    }
+    Some(settled)
+}
+
+#[test]
+fn test_resolve() {
+    let root = ::std::env::temp_dir().join(format!("hunk-test-resolve-{}", ::std::process::id()));
+    fs::create_dir_all(root.join("docs")).unwrap();
+    fs::create_dir_all(root.join("empty")).unwrap();
+    fs::create_dir_all(root.join("Photos")).unwrap();
+    // Symlinks are checked against the root's real path
+    let root = root.canonicalize().unwrap();
+    fs::write(root.join("docs/index.html"), "").unwrap();
+    fs::write(root.join("a.txt"), "").unwrap();
+    // NFD, as macOS would write it
+    fs::write(root.join("cafe\u{301}.txt"), "").unwrap();
+
+    let server = config::Server { root: root.clone(), ..config::Server::default() };
+    let no_index = config::Server { index: Vec::new(), ..server.clone() };
+    let fallback = config::Server {
+        unicode_normalization: ::lookup::UnicodeNormalization::NfcFallback,
+        case_insensitive: true,
+        ..server.clone()
+    };
+
+    let access = Access::default();
+    let exact = Lookup::default();
+    let lookup = Lookup::new(&fallback);
+
+    assert_eq!(resolve(&server, "/a.txt", None, &access, &exact), Ok(Resolved::File(root.join("a.txt"))));
+    assert_eq!(resolve(&server, "/missing.txt", None, &access, &exact), Err(PathError::NotFound));
+    assert_eq!(resolve(&server, "/docs/", None, &access, &exact), Ok(Resolved::File(root.join("docs/index.html"))));
+    assert_eq!(resolve(&server, "/docs", None, &access, &exact), Ok(Resolved::Redirect("/docs/".to_string())));
+    assert_eq!(resolve(&server, "/docs", Some("a=1"), &access, &exact), Ok(Resolved::Redirect("/docs/?a=1".to_string())));
+    assert_eq!(resolve(&server, "/empty/", None, &access, &exact), Ok(Resolved::Directory(root.join("empty/"))));
+    assert_eq!(resolve(&no_index, "/docs/", None, &access, &exact), Ok(Resolved::Directory(root.join("docs/"))));
+
+    // Collapsed slashes don't leak into the Location
+    assert_eq!(resolve(&server, "//docs", None, &access, &exact), Ok(Resolved::Redirect("/docs/".to_string())));
+    assert_eq!(resolve(&server, "/docs%2Findex.html", None, &access, &exact), Err(PathError::Malformed));
+
+    // Lookup fallback, e.g. /café.txt in NFC and /photos/ in the wrong case
+    assert_eq!(resolve(&server, "/caf%C3%A9.txt", None, &access, &exact), Err(PathError::NotFound));
+    assert_eq!(resolve(&fallback, "/caf%C3%A9.txt", None, &access, &lookup), Ok(Resolved::File(root.join("cafe\u{301}.txt"))));
+    assert_eq!(resolve(&fallback, "/A.TXT", None, &access, &lookup), Ok(Resolved::File(root.join("a.txt"))));
+    assert_eq!(resolve(&fallback, "/photos/", None, &access, &lookup), Ok(Resolved::Directory(root.join("Photos"))));
+    assert_eq!(resolve(&fallback, "/photos", None, &access, &lookup), Ok(Resolved::Redirect("/Photos/".to_string())));
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_try_files() {
+    let root = ::std::env::temp_dir().join(format!("hunk-test-try-files-{}", ::std::process::id()));
+    fs::create_dir_all(root.join("guide")).unwrap();
+    let root = root.canonicalize().unwrap();
+    fs::write(root.join("index.html"), "").unwrap();
+    fs::write(root.join("about.html"), "").unwrap();
+    fs::write(root.join("guide/index.html"), "").unwrap();
+
+    let candidates: Vec<String> = vec!["$uri", "$uri.html", "$uri/index.html", "/index.html"]
+        .into_iter()
+        .map(String::from)
+        .collect();
+
+    let server = config::Server {
+        root: root.clone(),
+        try_files: candidates,
+        try_files_skip_assets: true,
+        ..config::Server::default()
+    };
+    let no_skip = config::Server { try_files_skip_assets: false, ..server.clone() };
+    let no_candidates = config::Server { try_files: Vec::new(), ..no_skip.clone() };
+
+    let access = Access::default();
+    let lookup = Lookup::default();
+
+    assert_eq!(try_files(&server, "/about", &access, &lookup), Some(root.join("about.html")));
+    assert_eq!(try_files(&server, "/guide", &access, &lookup), Some(root.join("guide/index.html")));
+    assert_eq!(try_files(&server, "/some/spa/route", &access, &lookup), Some(root.join("index.html")));
+    // Assets still 404
+    assert_eq!(try_files(&server, "/missing.js", &access, &lookup), None);
+    assert_eq!(try_files(&no_skip, "/missing.js", &access, &lookup), Some(root.join("index.html")));
+    // Can't climb out of root
+    assert_eq!(try_files(&no_skip, "/../etc/passwd", &access, &lookup), None);
+    assert_eq!(try_files(&no_candidates, "/about", &access, &lookup), None);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_settle() {
+    let root = ::std::env::temp_dir().join(format!("hunk-test-settle-{}", ::std::process::id()));
+    fs::create_dir_all(root.join("Private")).unwrap();
+    fs::create_dir_all(root.join("empty")).unwrap();
+    let root = root.canonicalize().unwrap();
+    fs::write(root.join("Private/Secret File.txt"), "").unwrap();
+    fs::write(root.join("Private/index.html"), "").unwrap();
+    fs::write(root.join("index.html"), "").unwrap();
+
+    let server = config::Server {
+        root: root.clone(),
+        case_insensitive: true,
+        try_files: vec!["$uri".to_string(), "/index.html".to_string()],
+        ..config::Server::default()
+    };
+
+    let access = Access::default();
+    let lookup = Lookup::new(&server);
+
+    // (request path, browse, expected)
+    let table = vec![
+        ("/Private/Secret%20File.txt", false, Some("/Private/Secret%20File.txt")),
+        // Spelled the way it is on disk
+        ("/PRIVATE/secret%20file.txt", false, Some("/Private/Secret%20File.txt")),
+        ("//private/./secret%20file.txt", false, Some("/Private/Secret%20File.txt")),
+        ("/private/", false, Some("/Private/index.html")),
+        // A listing or the try_files fallback
+        ("/empty/", true, Some("/empty/")),
+        ("/empty/", false, Some("/index.html")),
+        ("/some/spa/route", false, Some("/index.html")),
+        // Only a redirect or an error
+        ("/private", false, None),
+        ("/a%2Fb", false, None),
+    ];
+
+    for (req_path, browse, expected) in table {
+        assert_eq!(expected.map(String::from), settle(&server, req_path, &access, &lookup, browse), "{:?}", req_path);
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_check_symlinks() {
+    use std::os::unix::fs::symlink;
+
+    let base = ::std::env::temp_dir().join(format!("hunk-test-symlinks-{}", ::std::process::id()));
+    fs::create_dir_all(base.join("root/real")).unwrap();
+    fs::create_dir_all(base.join("outside")).unwrap();
+    let base = base.canonicalize().unwrap();
+    let root = base.join("root");
+
+    fs::write(root.join("real/a.txt"), "").unwrap();
+    fs::write(base.join("outside/secret.txt"), "").unwrap();
+    symlink(root.join("real"), root.join("inside-link")).unwrap();
+    symlink(base.join("outside"), root.join("outside-link")).unwrap();
+
+    // (path, policy, expected)
+    let table = vec![
+        ("real/a.txt", Symlinks::Deny, Ok(())),
+        ("inside-link/a.txt", Symlinks::Follow, Ok(())),
+        ("inside-link/a.txt", Symlinks::WithinRoot, Ok(())),
+        ("inside-link/a.txt", Symlinks::Deny, Err(PathError::Forbidden)),
+        ("outside-link/secret.txt", Symlinks::Follow, Ok(())),
+        ("outside-link/secret.txt", Symlinks::WithinRoot, Err(PathError::NotFound)),
+        ("outside-link/secret.txt", Symlinks::Deny, Err(PathError::Forbidden)),
+        ("outside-link", Symlinks::WithinRoot, Err(PathError::NotFound)),
+        // Nothing there
+        ("missing.txt", Symlinks::Deny, Ok(())),
+    ];
+
+    for (path, symlinks, expected) in table {
+        assert_eq!(expected, check_symlinks(&root, &root.join(path), symlinks), "{:?} {:?}", path, symlinks);
+    }
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+// Property tests for RequestPath, fed random (mostly junk) strings by quickcheck.
+
+#[test]
+fn prop_segments_are_clean() {
+    fn prop(s: String) -> bool {
+        match RequestPath::parse(&format!("/{}", s)) {
+            Err(e) => e == PathError::Malformed,
+            Ok(path) => path.segments.iter().all(|segment| {
+                !segment.is_empty() && segment != "." && segment != ".."
+                    && !segment.chars().any(|c| c == '/' || c == '\\' || c.is_control())
+            }),
+        }
+    }
+    ::quickcheck::quickcheck(prop as fn(String) -> bool);
+}
+
+#[test]
+fn prop_canonical_is_stable() {
+    fn prop(s: String) -> bool {
+        match RequestPath::parse(&format!("/{}", s)) {
+            Err(_) => true,
+            Ok(path) => RequestPath::parse(&path.canonical) == Ok(path),
+        }
+    }
+    ::quickcheck::quickcheck(prop as fn(String) -> bool);
+}
+
+#[test]
+fn prop_encoded_segments_round_trip() {
+    use quickcheck::TestResult;
+
+    fn prop(segments: Vec<String>, extra_slashes: bool) -> TestResult {
+        // "/" has no segments, but "" isn't a request path
+        let clean = !segments.is_empty() && segments.iter().all(|segment| {
+            !segment.is_empty() && segment != "." && segment != ".."
+                && !segment.chars().any(|c| c == '/' || c == '\\' || c.is_control())
+        });
+        if !clean {
+            return TestResult::discard();
+        }
+
+        let separator = if extra_slashes { "//./" } else { "/" };
+        let req_path = segments.iter()
+            .map(|segment| pe::utf8_percent_encode(segment, pe::PATH_SEGMENT_ENCODE_SET).to_string())
+            .fold(String::new(), |acc, segment| acc + separator + &segment);
+
+        match RequestPath::parse(&req_path) {
+            Ok(path) => TestResult::from_bool(path.segments == segments),
+            Err(_) => TestResult::failed(),
+        }
+    }
+    ::quickcheck::quickcheck(prop as fn(Vec<String>, bool) -> TestResult);
+}
+
+#[test]
+fn prop_encoded_separators_are_rejected() {
+    fn prop(a: String, b: String, backslash: bool) -> bool {
+        let encode = |s: &str| pe::utf8_percent_encode(s, pe::PATH_SEGMENT_ENCODE_SET).to_string();
+        let separator = if backslash { "%5C" } else { "%2F" };
+        let req_path = format!("/{}{}{}", encode(&a), separator, encode(&b));
+        RequestPath::parse(&req_path) == Err(PathError::Malformed)
+    }
+    ::quickcheck::quickcheck(prop as fn(String, String, bool) -> bool);
+}
+
+#[test]
+fn prop_entity_path_stays_in_root() {
+    fn prop(s: String) -> bool {
+        let root = Path::new("/srv/www");
+        match get_entity_path(root, &format!("/{}", s), Dotfiles::Allow, &Access::default()) {
+            Err(_) => true,
+            Ok(path) => path.strip_prefix(root).map(is_safe_path).unwrap_or(false),
+        }
+    }
+    ::quickcheck::quickcheck(prop as fn(String) -> bool);
+}
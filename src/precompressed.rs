@@ -0,0 +1,66 @@
+// Precompressed sidecar files, e.g. app.js.br and app.js.gz next to app.js,
+// so that we can skip compressing on the fly.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use hyper::header;
+
+use compress::Encoder;
+use negotiation;
+
+pub struct Sidecar {
+    pub encoder: Encoder,
+    pub file: File,
+}
+
+// Returns the encoders the file has sidecars for and the sidecar the client prefers, if any.
+pub fn find(
+    path: &Path,
+    encoders: &[Encoder],
+    accept: Option<&header::AcceptEncoding>,
+) -> (Vec<Encoder>, Option<Sidecar>) {
+    let available = available(path, encoders);
+
+    let encoder = match negotiation::negotiate_encoding(accept, &available) {
+        Ok(Some(encoder)) => encoder,
+        _ => return (available, None),
+    };
+
+    let sidecar = sidecar_path(path, encoder)
+        .and_then(|path| File::open(path).ok())
+        .map(|file| Sidecar { encoder, file });
+
+    (available, sidecar)
+}
+
+// The encoders that have a sidecar next to the file at path.
+pub fn available(path: &Path, encoders: &[Encoder]) -> Vec<Encoder> {
+    encoders
+        .iter()
+        .cloned()
+        .filter(|encoder| {
+            sidecar_path(path, *encoder)
+                .and_then(|path| fs::metadata(path).ok())
+                .map(|meta| meta.is_file())
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+pub fn sidecar_path(path: &Path, encoder: Encoder) -> Option<PathBuf> {
+    let ext = encoder.extension()?;
+    let mut filename: OsString = path.file_name()?.to_os_string();
+    filename.push(".");
+    filename.push(ext);
+    Some(path.with_file_name(filename))
+}
+
+#[test]
+fn test_sidecar_path() {
+    assert_eq!(sidecar_path(Path::new("a/app.js"), Encoder::Brotli), Some(PathBuf::from("a/app.js.br")));
+    assert_eq!(sidecar_path(Path::new("a/app.js"), Encoder::Gzip), Some(PathBuf::from("a/app.js.gz")));
+    assert_eq!(sidecar_path(Path::new("a/app.js"), Encoder::Zstd), Some(PathBuf::from("a/app.js.zst")));
+    assert_eq!(sidecar_path(Path::new("a/app.js"), Encoder::Deflate), None);
+}
@@ -0,0 +1,139 @@
+// Conditional request evaluation
+// <https://tools.ietf.org/html/rfc7232#section-6>
+
+use hyper::{header, Headers, Method};
+
+use negotiation;
+
+#[derive(Debug, PartialEq)]
+pub enum Precondition {
+    // Carry on with the request, e.g. 200 or 206
+    Pass,
+
+    // 304 Not Modified
+    NotModified,
+
+    // 412 Precondition Failed
+    Failed,
+}
+
+// Evaluates the conditional request headers in the order defined by RFC 7232 section 6.
+//
+// If-Range is evaluated later alongside the Range header. See range::if_range.
+pub fn evaluate(
+    method: &Method,
+    headers: &Headers,
+    etag: Option<&header::EntityTag>,
+    last_modified: header::HttpDate,
+) -> Precondition {
+    let safe = *method == Method::Get || *method == Method::Head;
+
+    // 1. If-Match, else 2. If-Unmodified-Since
+    if headers.has::<header::IfMatch>() {
+        if !negotiation::any_match(headers.get::<header::IfMatch>(), etag) {
+            return Precondition::Failed;
+        }
+    } else if let Some(&header::IfUnmodifiedSince(since)) = headers.get() {
+        if last_modified > since {
+            return Precondition::Failed;
+        }
+    }
+
+    // 3. If-None-Match, else 4. If-Modified-Since
+    if headers.has::<header::IfNoneMatch>() {
+        if !negotiation::none_match(headers.get::<header::IfNoneMatch>(), etag) {
+            return if safe {
+                Precondition::NotModified
+            } else {
+                Precondition::Failed
+            };
+        }
+    } else if let Some(&header::IfModifiedSince(since)) = headers.get() {
+        // If-Modified-Since only applies to GET and HEAD.
+        if safe && last_modified <= since {
+            return Precondition::NotModified;
+        }
+    }
+
+    Precondition::Pass
+}
+
+#[test]
+fn test_evaluate() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let etag = header::EntityTag::strong("abc".to_string());
+    let other = header::EntityTag::strong("xyz".to_string());
+    let mtime = header::HttpDate::from(UNIX_EPOCH + Duration::from_secs(1_000_000));
+    let before = header::HttpDate::from(UNIX_EPOCH + Duration::from_secs(999_999));
+    let after = header::HttpDate::from(UNIX_EPOCH + Duration::from_secs(1_000_001));
+
+    fn headers(f: &dyn Fn(&mut Headers)) -> Headers {
+        let mut headers = Headers::new();
+        f(&mut headers);
+        headers
+    }
+
+    // (method, request headers, expected)
+    let table: Vec<(Method, Headers, Precondition)> = vec![
+        // Unconditional
+        (Method::Get, Headers::new(), Precondition::Pass),
+        // If-Match
+        (Method::Get, headers(&|h| h.set(header::IfMatch::Any)), Precondition::Pass),
+        (Method::Get, headers(&|h| h.set(header::IfMatch::Items(vec![etag.clone()]))), Precondition::Pass),
+        (Method::Get, headers(&|h| h.set(header::IfMatch::Items(vec![other.clone()]))), Precondition::Failed),
+        // If-Match uses strong comparison
+        (Method::Get, headers(&|h| h.set(header::IfMatch::Items(vec![header::EntityTag::weak("abc".to_string())]))), Precondition::Failed),
+        // If-Unmodified-Since
+        (Method::Get, headers(&|h| h.set(header::IfUnmodifiedSince(mtime))), Precondition::Pass),
+        (Method::Get, headers(&|h| h.set(header::IfUnmodifiedSince(before))), Precondition::Failed),
+        // If-Unmodified-Since is ignored when If-Match is present
+        (Method::Get, headers(&|h| {
+            h.set(header::IfMatch::Items(vec![etag.clone()]));
+            h.set(header::IfUnmodifiedSince(before));
+        }), Precondition::Pass),
+        // If-Match is evaluated before If-None-Match
+        (Method::Get, headers(&|h| {
+            h.set(header::IfMatch::Items(vec![other.clone()]));
+            h.set(header::IfNoneMatch::Items(vec![etag.clone()]));
+        }), Precondition::Failed),
+        // If-None-Match
+        (Method::Get, headers(&|h| h.set(header::IfNoneMatch::Items(vec![etag.clone()]))), Precondition::NotModified),
+        (Method::Head, headers(&|h| h.set(header::IfNoneMatch::Any)), Precondition::NotModified),
+        (Method::Get, headers(&|h| h.set(header::IfNoneMatch::Items(vec![other.clone()]))), Precondition::Pass),
+        // If-None-Match uses weak comparison
+        (Method::Get, headers(&|h| h.set(header::IfNoneMatch::Items(vec![header::EntityTag::weak("abc".to_string())]))), Precondition::NotModified),
+        // If-None-Match on unsafe methods fails instead
+        (Method::Put, headers(&|h| h.set(header::IfNoneMatch::Items(vec![etag.clone()]))), Precondition::Failed),
+        (Method::Delete, headers(&|h| h.set(header::IfNoneMatch::Any)), Precondition::Failed),
+        // If-Modified-Since
+        (Method::Get, headers(&|h| h.set(header::IfModifiedSince(mtime))), Precondition::NotModified),
+        (Method::Get, headers(&|h| h.set(header::IfModifiedSince(after))), Precondition::NotModified),
+        (Method::Get, headers(&|h| h.set(header::IfModifiedSince(before))), Precondition::Pass),
+        // If-Modified-Since is ignored for unsafe methods
+        (Method::Put, headers(&|h| h.set(header::IfModifiedSince(mtime))), Precondition::Pass),
+        // If-Modified-Since is ignored when If-None-Match is present
+        (Method::Get, headers(&|h| {
+            h.set(header::IfNoneMatch::Items(vec![other.clone()]));
+            h.set(header::IfModifiedSince(mtime));
+        }), Precondition::Pass),
+    ];
+
+    for (method, req_headers, expected) in table {
+        assert_eq!(
+            expected,
+            evaluate(&method, &req_headers, Some(&etag), mtime),
+            "{} {:?}", method, req_headers
+        );
+    }
+
+    // Without an ETag only "*" can match
+    let any_match = headers(&|h| h.set(header::IfMatch::Any));
+    assert_eq!(evaluate(&Method::Get, &any_match, None, mtime), Precondition::Pass);
+    let if_match = headers(&|h| h.set(header::IfMatch::Items(vec![etag.clone()])));
+    assert_eq!(evaluate(&Method::Get, &if_match, None, mtime), Precondition::Failed);
+    let any_none_match = headers(&|h| h.set(header::IfNoneMatch::Any));
+    assert_eq!(evaluate(&Method::Get, &any_none_match, None, mtime), Precondition::NotModified);
+    let if_none_match = headers(&|h| h.set(header::IfNoneMatch::Items(vec![etag.clone()])));
+    assert_eq!(evaluate(&Method::Get, &if_none_match, None, mtime), Precondition::Pass);
+}
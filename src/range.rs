@@ -1,8 +1,13 @@
 use std::ops::Range;
+use std::time::{Duration, SystemTime};
 
 use hyper::header::{self, ByteRangeSpec};
 use std::cmp;
 
+// Upper bound on the number of parts we'll put in a multipart/byteranges response.
+// Anything past this (after merging) gets the full entity instead.
+pub const MAX_RANGES: usize = 16;
+
 #[derive(Debug, PartialEq)]
 pub enum RequestedRange {
     // Client did not provide a range
@@ -13,6 +18,10 @@ pub enum RequestedRange {
 
     // We can serve the client's requested range
     Satisfiable(Range<u64>),
+
+    // Client requested more than one range, so we respond with multipart/byteranges.
+    // Ranges are sorted, merged, and never overlap.
+    Multiple(Vec<Range<u64>>),
 }
 
 pub fn parse_range_header(
@@ -21,14 +30,11 @@ pub fn parse_range_header(
     file_len: u64,
 ) -> RequestedRange {
     match header_value {
-        Some(&header::Range::Bytes(ref byte_ranges)) => {
-            // Get the first byte range or short-circuit if none given.
-            let byte_range = match byte_ranges.first() {
-                Some(range) =>
-                    range,
-                None =>
-                    return RequestedRange::NotSatisfiable,
-            };
+        Some(header::Range::Bytes(byte_ranges)) => {
+            // Short-circuit if no byte range given.
+            if byte_ranges.is_empty() {
+                return RequestedRange::NotSatisfiable;
+            }
 
             // Avoid overflow on zero-length file by short-circuiting if client tries
             // to define a range at all since even 0-0 is impossible.
@@ -36,86 +42,251 @@ pub fn parse_range_header(
                 return RequestedRange::NotSatisfiable;
             }
 
-            let max_end = file_len - 1;
-
-            let range = match *byte_range {
-                ByteRangeSpec::FromTo(start, end) => start..(cmp::min(max_end, end)),
-                ByteRangeSpec::AllFrom(start) => start..max_end,
-                ByteRangeSpec::Last(suffix_len) => {
-                    if suffix_len == 0 {
-                        return RequestedRange::NotSatisfiable;
-                    }
-                    // Ensure start cannot be negative
-                    let start = max_end - cmp::min(max_end, suffix_len + 1);
-                    start..max_end
-                }
-            };
-
-            // VALIDATION
-
-            // Bad range: start > end
-            // FIXME: This doesn't actually check anything because header_value goes to None branch if start > end.
-            // I'd prefer to respond NotSatisfiable.
-            if range.start > range.end {
-                return RequestedRange::NotSatisfiable;
-            }
+            // A range set is satisfiable if at least one of its ranges is,
+            // so we just drop the ones we can't serve.
+            // <https://tools.ietf.org/html/rfc7233#section-4.4>
+            let ranges: Vec<Range<u64>> = byte_ranges
+                .iter()
+                .filter_map(|spec| parse_byte_range_spec(spec, file_len))
+                .collect();
 
-            // Bad range: start >= resource length
-            // BAD: fileLength=10 and range is "10-"
-            // BAD: fileLength=10 and range is "10-10"
-            // GOOD: fileLength=10 and range is "9-"
-            if range.start > max_end {
-                return RequestedRange::NotSatisfiable;
-            }
+            let mut ranges = coalesce(ranges);
 
-            RequestedRange::Satisfiable(range)
+            match ranges.len() {
+                0 =>
+                    RequestedRange::NotSatisfiable,
+                1 =>
+                    RequestedRange::Satisfiable(ranges.remove(0)),
+                // Too many parts to be worth it, so just send the whole thing.
+                n if n > MAX_RANGES =>
+                    RequestedRange::None,
+                _ =>
+                    RequestedRange::Multiple(ranges),
+            }
         }
-        // We only support byte ranges.
-        Some(_) => RequestedRange::NotSatisfiable,
+        // We must ignore range units we don't understand.
+        // <https://tools.ietf.org/html/rfc7233#section-3.1>
+        Some(_) => RequestedRange::None,
+        // req.headers().get(header::Range) is also None when hyper can't parse the header,
+        // e.g. "bytes=5-2" where start > end. That's a syntactically invalid byte-range-set
+        // which must be ignored rather than rejected, so either way we serve the full entity.
+        // <https://tools.ietf.org/html/rfc7233#section-2.1>
         None => {
-            // req.headers().get(header::Range) will be None if start > end, so we need to
-            // check req.headers().has(header::Range) as well to differentiate between
-            // missing header and invalid header.
             if has_header {
-                RequestedRange::NotSatisfiable
-            } else {
-                RequestedRange::None
+                debug!("ignoring unparseable Range header");
             }
+            RequestedRange::None
         }
     }
 }
 
+// Returns the end-inclusive range of a single byte-range-spec or None if we can't satisfy it.
+//
+// Assumes file_len > 0.
+fn parse_byte_range_spec(spec: &ByteRangeSpec, file_len: u64) -> Option<Range<u64>> {
+    let max_end = file_len - 1;
+
+    let range = match *spec {
+        // "500-999": clamp end to the last byte
+        ByteRangeSpec::FromTo(start, end) => {
+            // hyper doesn't produce this, but the variant can be constructed by hand.
+            if start > end {
+                return None;
+            }
+            start..(cmp::min(max_end, end))
+        }
+        // "500-": everything from start
+        ByteRangeSpec::AllFrom(start) => start..max_end,
+        // "-500": the final 500 bytes, or the whole entity if it's shorter than that.
+        ByteRangeSpec::Last(suffix_len) => {
+            if suffix_len == 0 {
+                return None;
+            }
+            let start = file_len - cmp::min(file_len, suffix_len);
+            start..max_end
+        }
+    };
+
+    // Bad range: start >= resource length
+    // BAD: fileLength=10 and range is "10-"
+    // BAD: fileLength=10 and range is "10-10"
+    // GOOD: fileLength=10 and range is "9-"
+    if range.start > max_end {
+        return None;
+    }
+
+    Some(range)
+}
+
+// Returns false if the client's If-Range validator no longer matches our entity,
+// in which case we ignore the Range header and send the full entity.
+//
+// Only strong validators can match. A weak ETag never does, and a date only does
+// if it's an exact match for Last-Modified and Last-Modified is strong, i.e. at
+// least a second older than date, the time of the response. Otherwise the file
+// could have changed again within the same second.
+// <https://tools.ietf.org/html/rfc7233#section-3.2>
+// <https://tools.ietf.org/html/rfc7232#section-2.2.2>
+pub fn if_range(
+    has_header: bool,
+    header_value: Option<&header::IfRange>,
+    etag: Option<&header::EntityTag>,
+    last_modified: header::HttpDate,
+    date: header::HttpDate,
+) -> bool {
+    match header_value {
+        Some(header::IfRange::EntityTag(candidate)) =>
+            etag.map(|etag| candidate.strong_eq(etag)).unwrap_or(false),
+        Some(&header::IfRange::Date(candidate)) =>
+            candidate == last_modified && SystemTime::from(date)
+                .duration_since(SystemTime::from(last_modified))
+                .map(|age| age >= Duration::from_secs(1))
+                .unwrap_or(false),
+        // Can't validate a header we can't parse, so play it safe.
+        None =>
+            !has_header,
+    }
+}
+
+// Sort end-inclusive ranges and merge the ones that overlap or touch.
+//
+// Clients can send "0-99,50-149,150-199" and we'd rather send one part than three
+// since that's what the client will end up with anyways.
+pub fn coalesce(mut ranges: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    ranges.sort_unstable_by_key(|range| range.start);
+
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+
+    for range in ranges {
+        if let Some(prev) = merged.last_mut() {
+            if range.start <= prev.end.saturating_add(1) {
+                prev.end = cmp::max(prev.end, range.end);
+                continue;
+            }
+        }
+        merged.push(range);
+    }
+
+    merged
+}
+
 #[test]
-// TODO: Add more tests. Test failures. Consider using RangeInclusive.
 fn test_parse_range_header() {
-    // Range given but hyper can't parse it.
-    assert_eq!(
-        RequestedRange::NotSatisfiable,
-        parse_range_header(true, None, 0)
-    );
-    // Range not given at all
-    assert_eq!(RequestedRange::None, parse_range_header(false, None, 0));
+    use self::ByteRangeSpec::*;
+
+    // (header present, parsed byte ranges, file length, expected)
+    let table: Vec<(bool, Option<Vec<ByteRangeSpec>>, u64, RequestedRange)> = vec![
+        // Range not given at all
+        (false, None, 0, RequestedRange::None),
+        (false, None, 10, RequestedRange::None),
+        // Range given but hyper can't parse it, e.g. "bytes=5-2", so it's ignored
+        (true, None, 0, RequestedRange::None),
+        (true, None, 10, RequestedRange::None),
+        // Empty byte range set
+        (true, Some(vec![]), 10, RequestedRange::NotSatisfiable),
+        // Zero-length entity cannot be satisfied
+        (true, Some(vec![FromTo(0, 0)]), 0, RequestedRange::NotSatisfiable),
+        (true, Some(vec![AllFrom(0)]), 0, RequestedRange::NotSatisfiable),
+        (true, Some(vec![Last(1)]), 0, RequestedRange::NotSatisfiable),
+        // FromTo
+        (true, Some(vec![FromTo(0, 0)]), 1, RequestedRange::Satisfiable(0..0)),
+        (true, Some(vec![FromTo(0, 9)]), 10, RequestedRange::Satisfiable(0..9)),
+        (true, Some(vec![FromTo(2, 4)]), 10, RequestedRange::Satisfiable(2..4)),
+        (true, Some(vec![FromTo(9, 9)]), 10, RequestedRange::Satisfiable(9..9)),
+        (true, Some(vec![FromTo(5, 100)]), 10, RequestedRange::Satisfiable(5..9)),
+        (true, Some(vec![FromTo(10, 10)]), 10, RequestedRange::NotSatisfiable),
+        (true, Some(vec![FromTo(5, 2)]), 10, RequestedRange::NotSatisfiable),
+        // AllFrom
+        (true, Some(vec![AllFrom(0)]), 10, RequestedRange::Satisfiable(0..9)),
+        (true, Some(vec![AllFrom(9)]), 10, RequestedRange::Satisfiable(9..9)),
+        (true, Some(vec![AllFrom(10)]), 10, RequestedRange::NotSatisfiable),
+        // Last
+        (true, Some(vec![Last(3)]), 10, RequestedRange::Satisfiable(7..9)),
+        (true, Some(vec![Last(1)]), 10, RequestedRange::Satisfiable(9..9)),
+        (true, Some(vec![Last(10)]), 10, RequestedRange::Satisfiable(0..9)),
+        (true, Some(vec![Last(11)]), 10, RequestedRange::Satisfiable(0..9)),
+        (true, Some(vec![Last(1)]), 1, RequestedRange::Satisfiable(0..0)),
+        (true, Some(vec![Last(0)]), 10, RequestedRange::NotSatisfiable),
+        // Multiple
+        (true, Some(vec![FromTo(20, 29), FromTo(0, 9)]), 100, RequestedRange::Multiple(vec![0..9, 20..29])),
+        (true, Some(vec![FromTo(0, 9), Last(10)]), 100, RequestedRange::Multiple(vec![0..9, 90..99])),
+        // Overlapping and adjacent ranges collapse into a single range
+        (true, Some(vec![FromTo(0, 9), FromTo(5, 19), FromTo(20, 29)]), 100, RequestedRange::Satisfiable(0..29)),
+        (true, Some(vec![AllFrom(50), Last(10)]), 100, RequestedRange::Satisfiable(50..99)),
+        // Unsatisfiable ranges are dropped from the set
+        (true, Some(vec![FromTo(0, 9), FromTo(500, 599)]), 100, RequestedRange::Satisfiable(0..9)),
+        (true, Some(vec![FromTo(500, 599), Last(0)]), 100, RequestedRange::NotSatisfiable),
+    ];
+
+    for (has_header, specs, file_len, expected) in table {
+        let header_value = specs.clone().map(header::Range::Bytes);
+        assert_eq!(
+            expected,
+            parse_range_header(has_header, header_value.as_ref(), file_len),
+            "has_header={} specs={:?} file_len={}", has_header, specs, file_len
+        );
+    }
 
-    // Zero-length entity cannot be satisfied
+    // Unknown range units are ignored
     assert_eq!(
-        RequestedRange::NotSatisfiable,
+        RequestedRange::None,
         parse_range_header(
             true,
-            Some(&header::Range::Bytes(vec![
-                header::ByteRangeSpec::FromTo(0, 0),
-            ])),
-            0
+            Some(&header::Range::Unregistered("items".to_string(), "0-5".to_string())),
+            10
         )
     );
 
+    // Too many ranges falls back to the full entity
+    let specs = (0..(MAX_RANGES as u64 + 1))
+        .map(|i| FromTo(i * 2, i * 2))
+        .collect();
     assert_eq!(
-        RequestedRange::Satisfiable(0..0),
-        parse_range_header(
-            true,
-            Some(&header::Range::Bytes(vec![
-                header::ByteRangeSpec::FromTo(0, 0),
-            ])),
-            1
-        )
+        RequestedRange::None,
+        parse_range_header(true, Some(&header::Range::Bytes(specs)), 100)
     );
 }
+
+#[test]
+fn test_if_range() {
+    use std::time::UNIX_EPOCH;
+
+    let etag = header::EntityTag::strong("abc".to_string());
+    let mtime = header::HttpDate::from(UNIX_EPOCH + Duration::from_secs(1_000_000));
+    let earlier = header::HttpDate::from(UNIX_EPOCH + Duration::from_secs(999_999));
+    let now = header::HttpDate::from(UNIX_EPOCH + Duration::from_secs(2_000_000));
+
+    // (header present, parsed If-Range, expected)
+    let table: Vec<(bool, Option<header::IfRange>, bool)> = vec![
+        // No If-Range, so Range applies as usual
+        (false, None, true),
+        // Unparseable If-Range
+        (true, None, false),
+        (true, Some(header::IfRange::EntityTag(header::EntityTag::strong("abc".to_string()))), true),
+        (true, Some(header::IfRange::EntityTag(header::EntityTag::strong("xyz".to_string()))), false),
+        // Weak validators never match
+        (true, Some(header::IfRange::EntityTag(header::EntityTag::weak("abc".to_string()))), false),
+        (true, Some(header::IfRange::Date(mtime)), true),
+        (true, Some(header::IfRange::Date(earlier)), false),
+    ];
+
+    for (has_header, header_value, expected) in table {
+        assert_eq!(
+            expected,
+            if_range(has_header, header_value.as_ref(), Some(&etag), mtime, now),
+            "has_header={} header_value={:?}", has_header, header_value
+        );
+    }
+
+    // Without an ETag, only a date can validate
+    let header_value = header::IfRange::EntityTag(header::EntityTag::strong("abc".to_string()));
+    assert!(!if_range(true, Some(&header_value), None, mtime, now));
+
+    // A date only validates once Last-Modified is at least a second old
+    let header_value = header::IfRange::Date(mtime);
+    assert!(!if_range(true, Some(&header_value), Some(&etag), mtime, mtime));
+    let later = header::HttpDate::from(UNIX_EPOCH + Duration::from_secs(1_000_001));
+    assert!(if_range(true, Some(&header_value), Some(&etag), mtime, later));
+    // Or if the clock says it's from the future
+    assert!(!if_range(true, Some(&header_value), Some(&etag), mtime, earlier));
+}
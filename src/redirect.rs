@@ -0,0 +1,218 @@
+// Path patterns for [[redirect]] and [[rewrite]] rules.
+//
+// - `:name` captures one path segment, e.g. `/blog/:slug`
+// - A trailing `*` captures the rest of the path as `:splat`, e.g. `/docs/*`
+// - A trailing slash is optional on both sides, so `/about` also matches `/about/`
+//
+// Captures are substituted into the rule's `to`, e.g. `/posts/:slug` or `https://example.com/:splat`.
+//
+// Matching is done on the raw, still percent-encoded request path so that captures can
+// be pasted into a Location header as-is.
+
+use std::fmt;
+
+use regex::{self, Regex};
+
+#[derive(Clone)]
+pub struct Pattern {
+    pattern: String,
+    regex: Regex,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Pattern, String> {
+        if !pattern.starts_with('/') {
+            return Err(format!("pattern {:?} must start with /", pattern));
+        }
+
+        let (body, splat) = match pattern.strip_suffix('*') {
+            Some(body) => (body, true),
+            None => (pattern.trim_end_matches('/'), false),
+        };
+
+        let mut re = String::from("^");
+        for (i, segment) in body.split('/').enumerate() {
+            if i > 0 {
+                re.push('/');
+            }
+            if let Some(name) = segment.strip_prefix(':') {
+                if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                    return Err(format!("invalid capture {:?} in pattern {:?}", segment, pattern));
+                }
+                re.push_str(&format!("(?P<{}>[^/]+)", name));
+            } else if segment.contains('*') {
+                return Err(format!("* is only allowed at the end of pattern {:?}", pattern));
+            } else {
+                re.push_str(&regex::escape(segment));
+            }
+        }
+
+        if splat {
+            re.push_str("(?P<splat>.*)$");
+        } else {
+            re.push_str("/?$");
+        }
+
+        let regex = Regex::new(&re).map_err(|e| e.to_string())?;
+
+        Ok(Pattern { pattern: pattern.to_string(), regex })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn is_match(&self, path: &str) -> bool {
+        self.regex.is_match(path)
+    }
+
+    // Returns the (name, value) captures if the path matches.
+    fn captures(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let caps = self.regex.captures(path)?;
+        Some(self.regex.capture_names()
+            .flatten()
+            .filter_map(|name| caps.name(name).map(|m| (name.to_string(), m.as_str().to_string())))
+            .collect())
+    }
+}
+
+impl fmt::Debug for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Pattern({:?})", self.pattern)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub from: Pattern,
+    // Query params that must be present, e.g. `id = ":id"` captures it and
+    // `format = "rss"` requires that exact value.
+    pub query: Vec<(String, String)>,
+    pub to: String,
+}
+
+impl Rule {
+    pub fn new(from: &str, query: Vec<(String, String)>, to: String) -> Result<Rule, String> {
+        Ok(Rule { from: Pattern::new(from)?, query, to })
+    }
+
+    // Returns the destination, captures filled in, if the rule matches.
+    //
+    // The original query string is carried over unless the rule matched on it or
+    // the destination has its own.
+    pub fn apply(&self, path: &str, query: Option<&str>) -> Option<String> {
+        let mut captures = self.from.captures(path)?;
+
+        for (name, expected) in &self.query {
+            let actual = query
+                .into_iter()
+                .flat_map(|query| query.split('&'))
+                .map(|pair| {
+                    let mut parts = pair.splitn(2, '=');
+                    (parts.next().unwrap_or(""), parts.next().unwrap_or(""))
+                })
+                .find(|&(key, _)| key == name)
+                .map(|(_, value)| value)?;
+
+            if let Some(name) = expected.strip_prefix(':') {
+                captures.push((name.to_string(), actual.to_string()));
+            } else if expected != actual {
+                return None;
+            }
+        }
+
+        // Longest names first so that :id doesn't clobber the front of :ident
+        captures.sort_by_key(|capture| ::std::cmp::Reverse(capture.0.len()));
+
+        let mut to = substitute(&self.to, &captures);
+
+        // A capture can't turn a local destination into a protocol-relative one,
+        // e.g. /go/* to /:splat for /go//evil.example
+        if self.to.starts_with('/') {
+            let rest = to.trim_start_matches(['/', '\\']).to_string();
+            to = format!("/{}", rest);
+        }
+
+        match query {
+            Some(query) if self.query.is_empty() && !to.contains('?') && !query.is_empty() =>
+                Some(format!("{}?{}", to, query)),
+            _ =>
+                Some(to),
+        }
+    }
+}
+
+// Fills in the :name captures in one pass over to, so a value that itself
+// contains :name is pasted as-is rather than expanded again.
+fn substitute(to: &str, captures: &[(String, String)]) -> String {
+    let mut out = String::with_capacity(to.len());
+    let mut rest = to;
+    while let Some(i) = rest.find(':') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        match captures.iter().find(|&(name, _)| rest.starts_with(name.as_str())) {
+            Some((name, value)) => {
+                out.push_str(value);
+                rest = &rest[name.len()..];
+            }
+            None =>
+                out.push(':'),
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[test]
+fn test_apply() {
+    fn rule(from: &str, query: &[(&str, &str)], to: &str) -> Rule {
+        let query = query.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect();
+        Rule::new(from, query, to.to_string()).unwrap()
+    }
+
+    // (rule, path, query, expected)
+    let table = vec![
+        (rule("/old", &[], "/new"), "/old", None, Some("/new")),
+        (rule("/old", &[], "/new"), "/old/", None, Some("/new")),
+        (rule("/old/", &[], "/new"), "/old", None, Some("/new")),
+        (rule("/old", &[], "/new"), "/older", None, None),
+        (rule("/old", &[], "/new"), "/old", Some("a=1"), Some("/new?a=1")),
+        (rule("/blog/:slug", &[], "/posts/:slug"), "/blog/hello", None, Some("/posts/hello")),
+        (rule("/blog/:slug", &[], "/posts/:slug"), "/blog/a/b", None, None),
+        (rule("/blog/:year/:slug", &[], "/:year/:slug.html"), "/blog/2018/hi", None, Some("/2018/hi.html")),
+        (rule("/docs/*", &[], "https://docs.example.com/:splat"), "/docs/a/b.html", None, Some("https://docs.example.com/a/b.html")),
+        (rule("/docs/*", &[], "/v2/:splat"), "/docs/", None, Some("/v2/")),
+        (rule("/docs/*", &[], "/v2/:splat"), "/doc", None, None),
+        (rule("/:id/:ident", &[], "/:ident/:id"), "/1/x", None, Some("/x/1")),
+        // Captured values aren't expanded again
+        (rule("/:a/:b", &[], "/:b/:a"), "/:b/x", None, Some("/x/:b")),
+        (rule("/s/*", &[], "/t/:splat"), "/s/:splat:splat", None, Some("/t/:splat:splat")),
+        (rule("/store", &[("id", ":id")], "/p/:id"), "/store", Some("id=:splat"), Some("/p/:splat")),
+        // Or turned into a protocol-relative Location
+        (rule("/go/*", &[], "/:splat"), "/go//evil.example", None, Some("/evil.example")),
+        (rule("/go/*", &[], "/:splat"), "/go/\\evil.example", None, Some("/evil.example")),
+        (rule("/go/*", &[], "https://example.com/:splat"), "/go//x", None, Some("https://example.com//x")),
+        (rule("/old", &[], "https://example.com:8443/"), "/old", None, Some("https://example.com:8443/")),
+        // Regex metacharacters are literal
+        (rule("/a.b", &[], "/c"), "/axb", None, None),
+        // Query matching
+        (rule("/store", &[("id", ":id")], "/products/:id"), "/store", Some("id=42&x=1"), Some("/products/42")),
+        (rule("/store", &[("id", ":id")], "/products/:id"), "/store", Some("x=1"), None),
+        (rule("/store", &[("id", ":id")], "/products/:id"), "/store", None, None),
+        (rule("/feed", &[("format", "rss")], "/feed.xml"), "/feed", Some("format=rss"), Some("/feed.xml")),
+        (rule("/feed", &[("format", "rss")], "/feed.xml"), "/feed", Some("format=atom"), None),
+        (rule("/search", &[], "/find?engine=x"), "/search", Some("q=1"), Some("/find?engine=x")),
+    ];
+
+    for (rule, path, query, expected) in table {
+        assert_eq!(expected.map(String::from), rule.apply(path, query), "{:?} {:?} {:?}", rule, path, query);
+    }
+}
+
+#[test]
+fn test_invalid_pattern() {
+    assert!(Pattern::new("old").is_err());
+    assert!(Pattern::new("/a/*/b").is_err());
+    assert!(Pattern::new("/a/:").is_err());
+    assert!(Pattern::new("/a/:b-c").is_err());
+}
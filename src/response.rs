@@ -11,6 +11,26 @@ pub fn method_not_allowed() -> Response {
         .with_body(TEXT)
 }
 
+pub fn moved_permanently(location: String) -> Response {
+    redirect(StatusCode::MovedPermanently, location)
+}
+
+pub fn redirect(status: StatusCode, location: String) -> Response {
+    Response::new()
+        .with_status(status)
+        .with_header(header::Location::new(location))
+        .with_header(header::ContentLength(0))
+}
+
+pub fn bad_request() -> Response {
+    const TEXT: &str = "Bad request";
+    Response::new()
+        .with_status(StatusCode::BadRequest)
+        .with_header(header::ContentType::plaintext())
+        .with_header(header::ContentLength(TEXT.len() as u64))
+        .with_body(TEXT)
+}
+
 pub fn not_found() -> Response {
     const TEXT: &str = "Not found";
     Response::new()
@@ -20,6 +40,36 @@ pub fn not_found() -> Response {
         .with_body(TEXT)
 }
 
+// challenge is the WWW-Authenticate value, e.g. `Basic realm="Restricted"`
+pub fn unauthorized(challenge: String) -> Response {
+    const TEXT: &str = "Unauthorized";
+    let mut res = Response::new()
+        .with_status(StatusCode::Unauthorized)
+        .with_header(header::ContentType::plaintext())
+        .with_header(header::ContentLength(TEXT.len() as u64))
+        .with_body(TEXT);
+    res.headers_mut().set_raw("WWW-Authenticate", challenge);
+    res
+}
+
+pub fn forbidden() -> Response {
+    const TEXT: &str = "Forbidden";
+    Response::new()
+        .with_status(StatusCode::Forbidden)
+        .with_header(header::ContentType::plaintext())
+        .with_header(header::ContentLength(TEXT.len() as u64))
+        .with_body(TEXT)
+}
+
+pub fn not_acceptable() -> Response {
+    const TEXT: &str = "Not acceptable";
+    Response::new()
+        .with_status(StatusCode::NotAcceptable)
+        .with_header(header::ContentType::plaintext())
+        .with_header(header::ContentLength(TEXT.len() as u64))
+        .with_body(TEXT)
+}
+
 pub fn internal_server_error() -> Response {
     const TEXT: &str = "Internal server error";
     Response::new()
@@ -36,12 +86,25 @@ pub fn precondition_failed() -> Response {
         .with_header(header::ContentLength(0))
 }
 
-pub fn not_modified(etag: header::EntityTag) -> Response {
-    Response::new()
-        .with_status(StatusCode::NotModified)
-        .with_header(header::ETag(etag)) // Required in 304 response
-        .with_header(header::ContentType::plaintext())
-        .with_header(header::ContentLength(0))
+// A 304 carries the headers that the 200 would have had that a cache needs to
+// update its stored response, but none of the other representation metadata.
+// <https://tools.ietf.org/html/rfc7232#section-4.1>
+pub fn not_modified(headers: &header::Headers) -> Response {
+    let mut res = Response::new().with_status(StatusCode::NotModified);
+
+    for view in headers.iter() {
+        let keep = view.is::<header::ETag>()
+            || view.is::<header::LastModified>()
+            || view.is::<header::CacheControl>()
+            || view.is::<header::Expires>()
+            || view.is::<header::Vary>()
+            || view.is::<header::ContentLocation>();
+        if keep {
+            res.headers_mut().set_raw(view.name().to_string(), view.raw().clone());
+        }
+    }
+
+    res
 }
 
 pub fn invalid_range(entity_len: u64) -> Response {
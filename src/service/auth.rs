@@ -0,0 +1,98 @@
+// HTTP Basic auth for [auth]. Requests for a protected path without a valid
+// login get a 401 with a WWW-Authenticate challenge for the path's realm.
+//
+// Checking a bcrypt hash takes a while, so it runs on the pool. Logins that
+// already passed are remembered and let straight through.
+
+use std::rc::Rc;
+
+use futures::{future::ok, Future};
+use futures_cpupool::CpuPool;
+use hyper::{self, header, Request, Response, server::Service};
+
+use config::Auth as Config;
+use htpasswd::Htpasswd;
+use path;
+use response;
+use service::log;
+
+#[derive(Debug)]
+pub struct Auth<T> {
+    pool: &'static CpuPool,
+    config: &'static Option<Config>,
+    htpasswd: &'static Option<Htpasswd>,
+    // Also called once bcrypt has had its say
+    next: Rc<T>,
+}
+
+impl<T> Auth<T> {
+    pub fn new(pool: &'static CpuPool, config: &'static Option<Config>, htpasswd: &'static Option<Htpasswd>, next: T) -> Self where T: Service + 'static {
+        Auth { pool, config, htpasswd, next: Rc::new(next) }
+    }
+}
+
+impl<T> Service for Auth<T> where T: Service<Request = Request, Response = Response, Error = hyper::Error> + 'static {
+    type Request = T::Request;
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let (config, htpasswd) = match (self.config, self.htpasswd) {
+            (Some(config), Some(htpasswd)) =>
+                (config, htpasswd),
+            _ =>
+                return Box::new(self.next.call(req)),
+        };
+
+        // No rule covers a path that won't decode, and Root gives it a 400.
+        let (realm, users) = match path::decode_path(req.path()).ok().and_then(|path| config.protect(&path)) {
+            None =>
+                return Box::new(self.next.call(req)),
+            Some(protected) =>
+                protected,
+        };
+
+        let (user, password) = match req.headers().get::<header::Authorization<header::Basic>>() {
+            None =>
+                return Box::new(ok(challenge(realm))),
+            Some(header::Authorization(basic)) =>
+                (basic.username.clone(), basic.password.clone().unwrap_or_default()),
+        };
+
+        if let Some(users) = users {
+            if !users.contains(&user) {
+                return Box::new(ok(challenge(realm)));
+            }
+        }
+
+        if htpasswd.is_verified(&user, &password) {
+            return admit(&*self.next, req, user);
+        }
+
+        let next = Rc::clone(&self.next);
+
+        Box::new(self.pool.spawn_fn(move || Ok::<_, hyper::Error>((htpasswd.verify(&user, &password), user))).and_then(move |(valid, user)| -> Box<dyn Future<Item = Response, Error = hyper::Error>> {
+            if valid {
+                admit(&*next, req, user)
+            } else {
+                Box::new(ok(challenge(realm)))
+            }
+        }))
+    }
+}
+
+// Passes the request on and tells Log who it was. Log takes the marker back off.
+fn admit<T>(next: &T, req: Request, user: String) -> Box<dyn Future<Item = Response, Error = hyper::Error>>
+    where T: Service<Request = Request, Response = Response, Error = hyper::Error>,
+          T::Future: 'static,
+{
+    Box::new(next.call(req).map(move |mut res| {
+        res.headers_mut().set_raw(log::REMOTE_USER, user);
+        res
+    }))
+}
+
+fn challenge(realm: &str) -> Response {
+    response::unauthorized(format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm))
+}
@@ -6,7 +6,10 @@ use futures::{future::ok, Future};
 use hyper::{self, header, Request, Response, Method, server::{Service}};
 use maud::{Markup, DOCTYPE, html, PreEscaped};
 
-use config::Browse as Config;
+use access::Access;
+use lookup::Lookup;
+use config::{self, Browse as Config};
+use netlify;
 use path;
 use response;
 
@@ -16,13 +19,23 @@ const JS: &str = include_str!("../assets/browse.js");
 #[derive(Debug)]
 pub struct Browse<T> {
     config: &'static Option<Config>,
+    server: &'static config::Server,
     root: &'static Path,
+    access: &'static Access,
+    lookup: &'static Lookup,
     next: T,
 }
 
 impl<T> Browse<T> {
-    pub fn new(config: &'static Option<Config>, root: &'static Path, next: T) -> Self {
-        Browse { config, root, next }
+    pub fn new(
+        config: &'static Option<Config>,
+        server: &'static config::Server,
+        root: &'static Path,
+        access: &'static Access,
+        lookup: &'static Lookup,
+        next: T,
+    ) -> Self {
+        Browse { config, server, root, access, lookup, next }
     }
 }
 
@@ -30,7 +43,7 @@ impl<T> Service for Browse<T> where T: Service<Request = Request, Response = Res
     type Request = T::Request;
     type Response = T::Response;
     type Error = T::Error;
-    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
         let _config = match *self.config {
@@ -45,12 +58,18 @@ impl<T> Service for Browse<T> where T: Service<Request = Request, Response = Res
             return Box::new(self.next.call(req))
         }
 
-        let entity_path = match path::get_entity_path(self.root, req.path()) {
-            None => return Box::new(ok(response::not_found())),
-            Some(path) => path,
+        let entity_path = match path::resolve(self.server, req.path(), req.query(), self.access, self.lookup) {
+            // Nothing here, but Root may have a try_files fallback for it.
+            Err(path::PathError::NotFound) => return Box::new(self.next.call(req)),
+            Err(path::PathError::Malformed) => return Box::new(ok(response::bad_request())),
+            Err(path::PathError::Forbidden) => return Box::new(ok(response::forbidden())),
+            Ok(path::Resolved::Redirect(location)) => return Box::new(ok(response::moved_permanently(location))),
+            // A file or a directory's index file, so let Root serve it
+            Ok(path::Resolved::File(_)) => return Box::new(self.next.call(req)),
+            Ok(path::Resolved::Directory(path)) => path,
         };
 
-        match handle_folder(self.root, entity_path.as_path()) {
+        match handle_folder(self.root, entity_path.as_path(), self.server.dotfiles, self.server.symlinks, self.access) {
             Ok(response) =>
                 Box::new(ok(response)),
             // Not a directory
@@ -66,11 +85,23 @@ struct FolderItem {
     filename: String,
     href: String,
     metadata: fs::Metadata,
+    is_link: bool,
+    // Where a symlink lands as a path under the root. Links out of the root
+    // are only marked as links so that the listing doesn't reveal host paths.
+    link_target: Option<String>,
 }
 
-fn handle_folder(root: &Path, path: &Path) -> io::Result<Response> {
+fn handle_folder(root: &Path, path: &Path, dotfiles: path::Dotfiles, symlinks: path::Symlinks, access: &Access) -> io::Result<Response> {
+    let is_root = path == root;
+
     let mut entries: Vec<FolderItem> = fs::read_dir(path)?
         .filter_map(|result| result.ok())
+        // Netlify's _redirects and _headers are config, not content
+        .filter(|entry| !(is_root && netlify::is_config_file(&entry.file_name().to_string_lossy())))
+        // List exactly what we'd serve
+        .filter(|entry| dotfiles.allows(&entry.file_name().to_string_lossy()))
+        // Leave out links that Root would refuse to follow
+        .filter(|entry| path::check_symlinks(root, &entry.path(), symlinks).is_ok())
         .map(|entry| {
             fs::metadata(entry.path()).map(|metadata| {
                 let filename = match entry.path().file_name() {
@@ -78,13 +109,31 @@ fn handle_folder(root: &Path, path: &Path) -> io::Result<Response> {
                     Some(filename) => filename.to_string_lossy().to_string(),
                 };
 
-                let href = format!("/{}", entry.path().strip_prefix(root).unwrap().to_string_lossy());
+                // Folders get a trailing slash so we don't bounce off the redirect
+                let href = format!(
+                    "/{}{}",
+                    entry.path().strip_prefix(root).unwrap().to_string_lossy(),
+                    if metadata.is_dir() { "/" } else { "" }
+                );
+
+                let is_link = fs::symlink_metadata(entry.path())
+                    .map(|m| m.file_type().is_symlink())
+                    .unwrap_or(false);
+
+                let link_target = if is_link {
+                    entry.path().canonicalize().ok()
+                        .and_then(|target| target.strip_prefix(root).ok().map(|target| format!("/{}", target.to_string_lossy())))
+                } else {
+                    None
+                };
 
-                Some(FolderItem { filename, href, metadata })
+                Some(FolderItem { filename, href, metadata, is_link, link_target })
             })
         })
         .filter_map(|result| result.ok())
-        .filter_map(|item| item)
+        .flatten()
+        // [access] and .hunkignore
+        .filter(|item| access.allows(&item.href))
         .collect();
 
     // Sort folders first, and the sort by filename a-z
@@ -94,7 +143,7 @@ fn handle_folder(root: &Path, path: &Path) -> io::Result<Response> {
         .filter(|parent| parent.starts_with(root))
         .and_then(|parent| parent.strip_prefix(root).ok())
         .and_then(|path| path.to_str())
-        .map(|path| format!("/{}", path));
+        .map(|path| if path.is_empty() { "/".to_string() } else { format!("/{}/", path) });
 
     let html = render_html(parent_href, entries).into_string();
 
@@ -120,8 +169,8 @@ fn render_html(parent_href: Option<String>, entries: Vec<FolderItem>) -> Markup
         (DOCTYPE)
         html lang="en" {
             head {
-                meta charset="utf-8"
-                style (PreEscaped(CSS))
+                meta charset="utf-8";
+                style { (PreEscaped(CSS)) }
             }
             div {
                 (folder_count) " directories, "
@@ -131,23 +180,33 @@ fn render_html(parent_href: Option<String>, entries: Vec<FolderItem>) -> Markup
             table style="width: 100%" {
                 thead {
                     tr {
-                        th ""
-                        th ""
+                        th {}
+                        th {}
                     }
                 }
 
                 tbody{
                     @if let Some(href) = parent_href {
                         tr {
-                            td a.folder href=(href) { (PreEscaped("&uarr;")) " up" }
-                            td ""
+                            td { a.folder href=(path::encode_path(&href)) { (PreEscaped("&uarr;")) " up" } }
+                            td {}
                         }
                     }
 
-                    @for (FolderItem { filename, href, metadata }) in entries {
+                    @for FolderItem { filename, href, metadata, is_link, link_target } in entries {
                         tr.entry {
-                            td a class=(if metadata.is_dir() { "folder" } else { "file" }) href=(href) {
-                                span.filename (filename)
+                            td {
+                                a class=(if metadata.is_dir() { "folder" } else { "file" }) href=(path::encode_path(&href)) {
+                                    span.filename { (filename) }
+                                }
+                                @if is_link {
+                                    span.target {
+                                        " " (PreEscaped("&rarr;"))
+                                        @if let Some(target) = link_target {
+                                            " " (target)
+                                        }
+                                    }
+                                }
                             }
                             td.size {
                                 @if metadata.is_dir() {
@@ -160,7 +219,7 @@ fn render_html(parent_href: Option<String>, entries: Vec<FolderItem>) -> Markup
                     }
                 }
             }
-            script (PreEscaped(JS))
+            script { (PreEscaped(JS)) }
         }
     }
 }
@@ -180,3 +239,27 @@ fn pretty_bytes(num: f64) -> String {
     let unit = UNITS[exponent as usize];
     format!("{}{} {}", negative, pretty_bytes, unit)
 }
+
+#[test]
+fn test_link_targets() {
+    use std::os::unix::fs::symlink;
+    use futures::Stream;
+
+    let base = ::std::env::temp_dir().join(format!("hunk-test-browse-links-{}", ::std::process::id()));
+    fs::create_dir_all(base.join("root/real")).unwrap();
+    fs::create_dir_all(base.join("outside")).unwrap();
+    let base = base.canonicalize().unwrap();
+    let root = base.join("root");
+    symlink(root.join("real"), root.join("inside-link")).unwrap();
+    symlink(base.join("outside"), root.join("outside-link")).unwrap();
+
+    let res = handle_folder(&root, &root, path::Dotfiles::Ignore, path::Symlinks::Follow, &Access::default()).unwrap();
+    let html = String::from_utf8(res.body().concat2().wait().unwrap().to_vec()).unwrap();
+
+    assert!(html.contains("inside-link"));
+    assert!(html.contains("&rarr; /real"));
+    assert!(html.contains("outside-link"));
+    assert!(!html.contains(base.join("outside").to_string_lossy().as_ref()));
+
+    fs::remove_dir_all(&base).unwrap();
+}
@@ -0,0 +1,113 @@
+// Sets Cache-Control and Expires by path glob.
+//
+// 304s get the same headers as the 200 they stand in for, else a revalidated
+// response would fall back to heuristic freshness.
+// <https://tools.ietf.org/html/rfc7232#section-4.1>
+
+use std::time::{Duration, SystemTime};
+
+use futures::Future;
+use hyper::{self, header, Request, Response, StatusCode, server::Service};
+
+use config::Cache as Config;
+use path;
+
+#[derive(Debug)]
+pub struct Cache<T> {
+    config: &'static Option<Config>,
+    next: T,
+}
+
+impl<T> Cache<T> {
+    pub fn new(config: &'static Option<Config>, next: T) -> Self where T: Service + 'static {
+        Cache { config, next }
+    }
+}
+
+impl<T> Service for Cache<T> where T: Service<Request = Request, Response = Response, Error = hyper::Error> + 'static {
+    type Request = T::Request;
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let config = match *self.config {
+            None =>
+                return Box::new(self.next.call(req)),
+            Some(ref config) =>
+                config,
+        };
+
+        let cache_control = match path::decode_path(req.path()).ok()
+            .and_then(|path| config.cache_control(&path).cloned())
+        {
+            None =>
+                return Box::new(self.next.call(req)),
+            Some(cache_control) =>
+                cache_control,
+        };
+
+        Box::new(self.next.call(req).map(move |mut res| {
+            if !res.status().is_success() && res.status() != StatusCode::NotModified {
+                return res;
+            }
+
+            // Downstream knows better
+            if res.headers().has::<header::CacheControl>() {
+                return res;
+            }
+
+            if let Some(expires) = expires(&cache_control, SystemTime::now()) {
+                res.headers_mut().set(header::Expires(header::HttpDate::from(expires)));
+            }
+            res.headers_mut().set(cache_control);
+
+            res
+        }))
+    }
+}
+
+// Expires for HTTP/1.0 caches that don't understand Cache-Control.
+//
+// - max-age=n: n seconds from now
+// - no-cache, no-store: already stale
+// - otherwise: None, e.g. "private" alone says nothing about freshness
+fn expires(cache_control: &header::CacheControl, now: SystemTime) -> Option<SystemTime> {
+    let mut expires = None;
+    for directive in cache_control.iter() {
+        match *directive {
+            header::CacheDirective::NoCache | header::CacheDirective::NoStore =>
+                return Some(now),
+            header::CacheDirective::MaxAge(secs) =>
+                expires = Some(now + Duration::from_secs(u64::from(secs))),
+            _ =>
+                {},
+        }
+    }
+    expires
+}
+
+#[test]
+fn test_expires() {
+    use std::time::UNIX_EPOCH;
+
+    fn parse(s: &str) -> header::CacheControl {
+        header::Header::parse_header(&header::Raw::from(s.to_string())).unwrap()
+    }
+
+    let now = UNIX_EPOCH + Duration::from_secs(1_000);
+
+    // (Cache-Control, expected)
+    let table = vec![
+        ("public, max-age=60", Some(now + Duration::from_secs(60))),
+        ("public, max-age=31536000, immutable", Some(now + Duration::from_secs(31_536_000))),
+        ("max-age=0", Some(now)),
+        ("no-cache", Some(now)),
+        ("no-store, max-age=60", Some(now)),
+        ("private", None),
+    ];
+
+    for (cache_control, expected) in table {
+        assert_eq!(expected, expires(&parse(cache_control), now), "{:?}", cache_control);
+    }
+}
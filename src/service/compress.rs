@@ -1,35 +1,42 @@
-use futures::{Future};
+use std::rc::Rc;
+
+use futures::{future::ok, Future, Stream};
 use hyper::server::{Request, Response, Service};
-use hyper::{header, Method};
-use flate2::Compression;
+use hyper::{self, header, Method, StatusCode};
 use unicase::Ascii;
 
 use negotiation;
 use mime;
 use compress;
+use response;
 use util;
 use config;
+use etag;
+
+// Compressed responses whose uncompressed length is at most this are buffered in full.
+const BUFFER_LIMIT: u64 = 65_536;
 
 #[derive(Debug)]
 pub struct Compress<T> {
     pool: &'static ::futures_cpupool::CpuPool,
-    config: &'static Option<config::Gzip>,
-    next: T,
+    config: &'static Option<config::Compress>,
+    // Called twice for a small HEAD
+    next: Rc<T>,
 }
 
 impl<T> Compress<T> {
-    pub fn new(pool: &'static ::futures_cpupool::CpuPool, config: &'static Option<config::Gzip>, next: T) -> Self where T: Service + 'static {
-        Compress { pool, config, next }
+    pub fn new(pool: &'static ::futures_cpupool::CpuPool, config: &'static Option<config::Compress>, next: T) -> Self where T: Service + 'static {
+        Compress { pool, config, next: Rc::new(next) }
     }
 }
 
 impl<T> Service for Compress<T>
-    where T: Service<Request = Request, Response = Response> + 'static
+    where T: Service<Request = Request, Response = Response, Error = hyper::Error> + 'static
 {
     type Request = T::Request;
     type Response = T::Response;
     type Error = T::Error;
-    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
         let config = match *self.config {
@@ -44,66 +51,364 @@ impl<T> Service for Compress<T>
             return Box::new(self.next.call(req))
         }
 
+        // Only held against the client if the response turns out to need encoding
+        let negotiated = negotiation::negotiate_encoding(
+            req.headers().get::<header::AcceptEncoding>(),
+            &config.encoders,
+        );
+
         let pool = self.pool.clone();
-        let req_accept_encoding = req.headers().get::<header::AcceptEncoding>().cloned();
+        let next = Rc::clone(&self.next);
 
+        // A HEAD has no body to measure, so keep what we need to ask for the GET.
+        let head = if *req.method() == Method::Head {
+            Some((req.uri().clone(), req.version(), req.headers().clone()))
+        } else {
+            None
+        };
 
-        Box::new(self.next.call(req).map(move |mut res| {
-            // Only compress if successful response
+        Box::new(self.next.call(req).and_then(move |mut res| -> Box<dyn Future<Item = Response, Error = hyper::Error>> {
+            // Only compress if successful response. A 304 has no Content-Type to check,
+            // so Root gives it the Vary and ETag that we'd give the 200.
             if !res.status().is_success() {
-                return res
+                return Box::new(ok(res))
+            }
+
+            // Byte ranges refer to the identity representation, so compressing a 206
+            // would hand the client a gzip of some slice that it can't stitch together.
+            // Root already serves precompressed sidecars as their own representation.
+            // <https://tools.ietf.org/html/rfc7233#section-2.1>
+            if res.status() == StatusCode::PartialContent {
+                return Box::new(ok(res))
             }
 
             let mime = match res.headers().get::<header::ContentType>() {
                 None =>
-                    return res,
-                Some(&header::ContentType(ref mime)) =>
+                    return Box::new(ok(res)),
+                Some(header::ContentType(mime)) =>
                     mime,
             };
 
             // Content-Length is always set by Root service
             let content_length = match res.headers().get::<header::ContentLength>() {
                 None =>
-                    return res,
+                    return Box::new(ok(res)),
                 Some(&header::ContentLength(length)) =>
                     length,
             };
 
-            let should_compress = mime::is_mime_compressible(mime) && content_length >= config.threshold && {
-                let encoding = negotiation::negotiate_encoding(req_accept_encoding.as_ref());
-                encoding == Some(header::Encoding::Gzip)
-            };
+            if !is_compressible(config, mime, content_length) {
+                return Box::new(ok(res))
+            }
+
+            // Vary even if the client didn't accept any of our encoders since
+            // the next client might.
+            util::append_header_vary(res.headers_mut(), Ascii::new("Accept-Encoding".to_string()));
 
-            if !should_compress {
-                return res
+            // Already encoded, e.g. Root served a precompressed sidecar.
+            if res.headers().has::<header::ContentEncoding>() {
+                return Box::new(ok(res))
             }
 
-            // Remove Content-Length
-            res.headers_mut().set(header::TransferEncoding(vec![header::Encoding::Chunked]));
-            res.headers_mut().remove::<header::ContentLength>();
+            // The client refused identity too, and this is a response we'd encode.
+            let encoder = match negotiated {
+                Err(negotiation::NotAcceptable) => {
+                    let mut res = response::not_acceptable();
+                    util::append_header_vary(res.headers_mut(), Ascii::new("Accept-Encoding".to_string()));
+                    return Box::new(ok(res))
+                }
+                Ok(None) =>
+                    return Box::new(ok(res)),
+                Ok(Some(encoder)) =>
+                    encoder,
+            };
 
             // Set Content-Encoding
-            res.headers_mut().set(header::ContentEncoding(vec![header::Encoding::Gzip]));
+            res.headers_mut().set(header::ContentEncoding(vec![encoder.header()]));
 
-            // Append Accept-Encoding
-            util::append_header_vary(&mut res.headers_mut(), Ascii::new("Accept-Encoding".to_string()));
+            // Weaken ETag, if the [etag] strategy gave us one.
+            // This also makes If-Range fail for the compressed representation so that
+            // a client resuming it gets a fresh 200 rather than a mismatched 206.
+            etag::weaken(res.headers_mut());
 
-            // Weaken ETag
-            if let Some(etag) = res.headers().get::<header::ETag>() {
-                if !etag.weak {
-                    let etag = header::EntityTag::weak(etag.tag().to_string());
-                    res.headers_mut().set::<header::ETag>(header::ETag(etag))
-                }
-            }
+            // We can't serve ranges of the compressed bytes.
+            res.headers_mut().set(header::AcceptRanges(vec![header::RangeUnit::None]));
 
             // TODO: Would be nicer to have something like:
             //
             //     let (head, body) = response.split();
             //     let response = Response::join(head, transform(body))
-            Response::new()
-                .with_status(res.status())
-                .with_headers(res.headers().clone())
-                .with_body(compress::gzip(&pool, Compression::new(1), res.body()))
+            let status = res.status();
+            let mut headers = res.headers().clone();
+            headers.remove::<header::ContentLength>();
+
+            // Small bodies are cheap to hold onto, so we buffer them to send an exact
+            // Content-Length instead of chunking. A HEAD gets the same Content-Length
+            // by compressing the GET's body and dropping it.
+            if content_length <= BUFFER_LIMIT {
+                let level = config.level(encoder);
+                let is_head = head.is_some();
+                let body: Box<dyn Future<Item = hyper::Chunk, Error = hyper::Error>> = match head {
+                    None =>
+                        Box::new(compress::encode(&pool, encoder, level, res.body()).concat2()),
+                    Some((uri, version, req_headers)) => {
+                        let mut get = Request::new(Method::Get, uri);
+                        get.set_version(version);
+                        *get.headers_mut() = req_headers;
+                        Box::new(next.call(get).and_then(move |res| compress::encode(&pool, encoder, level, res.body()).concat2()))
+                    }
+                };
+                return Box::new(body.map(move |chunk| {
+                    headers.set(header::ContentLength(chunk.len() as u64));
+                    let res = Response::new()
+                        .with_status(status)
+                        .with_headers(headers);
+                    if is_head { res } else { res.with_body(chunk) }
+                }))
+            }
+
+            let body = compress::encode(&pool, encoder, config.level(encoder), res.body());
+
+            headers.set(header::TransferEncoding(vec![header::Encoding::Chunked]));
+
+            Box::new(ok(Response::new()
+                .with_status(status)
+                .with_headers(headers)
+                .with_body(body)))
         }))
     }
 }
+
+// Whether a 200 with this Content-Type and length gets compressed, given a client that accepts it.
+pub fn is_compressible(config: &config::Compress, mime: &::hyper::mime::Mime, len: u64) -> bool {
+    mime::is_mime_compressible(mime) && len >= config.threshold
+}
+
+#[cfg(test)]
+struct Fixed(fn() -> Response);
+
+#[cfg(test)]
+impl Service for Fixed {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = ::futures::future::FutureResult<Response, hyper::Error>;
+
+    fn call(&self, _req: Request) -> Self::Future {
+        ok((self.0)())
+    }
+}
+
+#[cfg(test)]
+fn call_compress(accept_encoding: &'static str, res: fn() -> Response) -> (StatusCode, hyper::Headers, Vec<u8>) {
+    use leak::Leak;
+    use toml;
+
+    let pool = Box::new(::futures_cpupool::CpuPool::new(1)).leak();
+    let config = Box::new(Some(toml::from_str::<config::Compress>("").unwrap())).leak();
+    let service = Compress::new(pool, config, Fixed(res));
+
+    let mut req = Request::new(Method::Get, "/".parse().unwrap());
+    req.headers_mut().set_raw("Accept-Encoding", accept_encoding);
+
+    let res = service.call(req).wait().unwrap();
+    let status = res.status();
+    let headers = res.headers().clone();
+    let body = res.body().concat2().wait().unwrap().to_vec();
+    (status, headers, body)
+}
+
+#[cfg(test)]
+fn html_response(status: StatusCode) -> Response {
+    let body = "<p>hello</p>".repeat(200);
+    Response::new()
+        .with_status(status)
+        .with_header(header::ContentType::html())
+        .with_header(header::ContentLength(body.len() as u64))
+        .with_header(header::ETag(header::EntityTag::strong("abc".to_string())))
+        .with_header(header::AcceptRanges(vec![header::RangeUnit::Bytes]))
+        .with_body(body)
+}
+
+#[test]
+fn test_compresses_full_response() {
+    use std::io::Read;
+    use flate2::read::GzDecoder;
+
+    let (status, headers, body) = call_compress("gzip", || html_response(StatusCode::Ok));
+
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(headers.get(), Some(&header::ContentEncoding(vec![header::Encoding::Gzip])));
+    assert_eq!(headers.get(), Some(&header::ETag(header::EntityTag::weak("abc".to_string()))));
+    assert_eq!(headers.get(), Some(&header::AcceptRanges(vec![header::RangeUnit::None])));
+    assert_eq!(headers.get(), Some(&header::ContentLength(body.len() as u64)));
+
+    let mut decoded = String::new();
+    GzDecoder::new(&body[..]).read_to_string(&mut decoded).unwrap();
+    assert_eq!(decoded, "<p>hello</p>".repeat(200));
+}
+
+#[test]
+fn test_does_not_compress_partial_content() {
+    let (status, headers, body) = call_compress("gzip", || html_response(StatusCode::PartialContent));
+
+    assert_eq!(status, StatusCode::PartialContent);
+    assert!(!headers.has::<header::ContentEncoding>());
+    assert_eq!(headers.get(), Some(&header::ETag(header::EntityTag::strong("abc".to_string()))));
+    assert_eq!(headers.get(), Some(&header::AcceptRanges(vec![header::RangeUnit::Bytes])));
+    assert_eq!(body, "<p>hello</p>".repeat(200).into_bytes());
+}
+
+#[test]
+fn test_does_not_recompress_sidecar() {
+    let (_, headers, body) = call_compress("br, gzip", || {
+        html_response(StatusCode::PartialContent)
+            .with_header(header::ContentEncoding(vec![header::Encoding::Brotli]))
+    });
+
+    assert_eq!(headers.get(), Some(&header::ContentEncoding(vec![header::Encoding::Brotli])));
+    assert_eq!(headers.get(), Some(&header::ETag(header::EntityTag::strong("abc".to_string()))));
+    assert_eq!(body, "<p>hello</p>".repeat(200).into_bytes());
+}
+
+#[test]
+fn test_not_acceptable_only_when_encoding_is_required() {
+    fn not_modified() -> Response {
+        Response::new().with_status(StatusCode::NotModified)
+    }
+    fn image() -> Response {
+        html_response(StatusCode::Ok).with_header(header::ContentType::png())
+    }
+
+    let refuses = "identity;q=0, lzma";
+
+    assert_eq!(call_compress(refuses, || html_response(StatusCode::Ok)).0, StatusCode::NotAcceptable);
+    assert_eq!(call_compress(refuses, || html_response(StatusCode::NotFound)).0, StatusCode::NotFound);
+    assert_eq!(call_compress(refuses, || html_response(StatusCode::PartialContent)).0, StatusCode::PartialContent);
+    assert_eq!(call_compress(refuses, not_modified).0, StatusCode::NotModified);
+    assert_eq!(call_compress(refuses, image).0, StatusCode::Ok);
+}
+
+// Compress over the real Root, the way serve() stacks them, so that Root's
+// range handling and our own headers are tested together.
+#[test]
+fn test_ranges_through_root() {
+    use std::fs;
+    use std::io::Read;
+    use flate2::read::GzDecoder;
+    use leak::Leak;
+    use toml;
+    use access::Access;
+    use etag::ETagger;
+    use lookup::Lookup;
+    use service::root::Root;
+
+    let root = ::std::env::temp_dir().join(format!("hunk-test-compress-ranges-{}", ::std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    let root = root.canonicalize().unwrap();
+    let text = "<p>hello</p>".repeat(200);
+    fs::write(root.join("index.html"), &text).unwrap();
+
+    let config: &'static config::Config = Box::new(toml::from_str::<config::Config>(&format!(r#"
+        [server]
+        root = {:?}
+
+        [compress]
+    "#, root)).unwrap()).leak();
+
+    let pool = Box::new(::futures_cpupool::CpuPool::new(1)).leak();
+    let file_cache = Box::new(None).leak();
+    let etagger = Box::new(ETagger::new(Default::default())).leak();
+    let access = Box::new(Access::default()).leak();
+    let lookup = Box::new(Lookup::default()).leak();
+    let service = Compress::new(
+        pool,
+        &config.compress,
+        Root::new(pool, &config.server, &config.compress, file_cache, etagger, access, lookup),
+    );
+
+    let call = |headers: &[(&'static str, String)]| {
+        let mut req = Request::new(Method::Get, "/index.html".parse().unwrap());
+        req.headers_mut().set_raw("Accept-Encoding", "gzip");
+        for &(name, ref value) in headers {
+            req.headers_mut().set_raw(name, value.clone());
+        }
+        let res = service.call(req).wait().unwrap();
+        let status = res.status();
+        let headers = res.headers().clone();
+        (status, headers, res.body().concat2().wait().unwrap().to_vec())
+    };
+
+    // A full response is compressed, with a weak tag and no ranges
+    let (status, headers, body) = call(&[]);
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(headers.get(), Some(&header::ContentEncoding(vec![header::Encoding::Gzip])));
+    assert_eq!(headers.get(), Some(&header::AcceptRanges(vec![header::RangeUnit::None])));
+    let weak = match headers.get::<header::ETag>() {
+        Some(header::ETag(tag)) if tag.weak => tag.clone(),
+        other => panic!("expected a weak ETag, got {:?}", other),
+    };
+    let mut decoded = String::new();
+    GzDecoder::new(&body[..]).read_to_string(&mut decoded).unwrap();
+    assert_eq!(decoded, text);
+
+    // A HEAD gets the same headers, down to the Content-Length
+    let mut req = Request::new(Method::Head, "/index.html".parse().unwrap());
+    req.headers_mut().set_raw("Accept-Encoding", "gzip");
+    let res = service.call(req).wait().unwrap();
+    assert_eq!(res.status(), StatusCode::Ok);
+    assert_eq!(res.headers().get(), Some(&header::ContentLength(body.len() as u64)));
+    assert!(!res.headers().has::<header::TransferEncoding>());
+    assert_eq!(res.headers().get(), headers.get::<header::ETag>());
+    assert!(res.body().concat2().wait().unwrap().is_empty());
+
+    // A range is served from the identity bytes, untouched
+    let (status, headers, body) = call(&[("Range", "bytes=0-11".to_string())]);
+    assert_eq!(status, StatusCode::PartialContent);
+    assert!(!headers.has::<header::ContentEncoding>());
+    assert_eq!(headers.get(), Some(&header::AcceptRanges(vec![header::RangeUnit::Bytes])));
+    let strong = match headers.get::<header::ETag>() {
+        Some(header::ETag(tag)) if !tag.weak => tag.clone(),
+        other => panic!("expected a strong ETag, got {:?}", other),
+    };
+    assert_eq!(strong.tag(), weak.tag());
+    assert_eq!(body, b"<p>hello</p>".to_vec());
+
+    // Resuming with the strong tag gets the range
+    let (status, _, body) = call(&[("Range", "bytes=0-11".to_string()), ("If-Range", strong.to_string())]);
+    assert_eq!(status, StatusCode::PartialContent);
+    assert_eq!(body, b"<p>hello</p>".to_vec());
+
+    // Resuming with the weak tag of the compressed response gets the whole thing again
+    let (status, headers, _) = call(&[("Range", "bytes=0-11".to_string()), ("If-Range", weak.to_string())]);
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(headers.get(), Some(&header::ContentEncoding(vec![header::Encoding::Gzip])));
+
+    // Revalidating the compressed body keeps its tag weak, so the client can't turn
+    // around and splice an identity range onto it
+    let (status, headers, _) = call(&[("If-None-Match", weak.to_string())]);
+    assert_eq!(status, StatusCode::NotModified);
+    assert_eq!(headers.get(), Some(&header::ETag(weak.clone())));
+    assert_eq!(headers.get(), Some(&header::Vary::Items(vec![Ascii::new("Accept-Encoding".to_string())])));
+    let revalidated = headers.get::<header::ETag>().unwrap().0.to_string();
+    let (status, headers, _) = call(&[("Range", "bytes=0-11".to_string()), ("If-Range", revalidated)]);
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(headers.get(), Some(&header::ContentEncoding(vec![header::Encoding::Gzip])));
+
+    // An image's 304 is left alone, like its 200
+    fs::write(root.join("image.png"), "<p>hello</p>".repeat(200)).unwrap();
+    let mut req = Request::new(Method::Get, "/image.png".parse().unwrap());
+    req.headers_mut().set_raw("Accept-Encoding", "gzip");
+    let res = service.call(req).wait().unwrap();
+    let strong = res.headers().get::<header::ETag>().unwrap().clone();
+    assert!(!strong.0.weak);
+    let mut req = Request::new(Method::Get, "/image.png".parse().unwrap());
+    req.headers_mut().set_raw("Accept-Encoding", "gzip");
+    req.headers_mut().set(header::IfNoneMatch::Items(vec![strong.0.clone()]));
+    let res = service.call(req).wait().unwrap();
+    assert_eq!(res.status(), StatusCode::NotModified);
+    assert_eq!(res.headers().get(), Some(&strong));
+    assert!(!res.headers().has::<header::Vary>());
+
+    fs::remove_dir_all(&root).unwrap();
+}
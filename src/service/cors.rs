@@ -27,7 +27,7 @@ impl<T> Service for Cors<T> where T: Service<Request = Request, Response = Respo
     type Request = T::Request;
     type Response = T::Response;
     type Error = T::Error;
-    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
         let config = match *self.config {
@@ -54,7 +54,7 @@ impl<T> Service for Cors<T> where T: Service<Request = Request, Response = Respo
 
         if *req.method() == Method::Options {
             let mut res = Response::new();
-            util::append_header_vary(&mut res.headers_mut(), Ascii::new("Origin".to_string()));
+            util::append_header_vary(res.headers_mut(), Ascii::new("Origin".to_string()));
             res.headers_mut().set(header::ContentLength(0));
             res.headers_mut().set(header::ContentType(hyper::mime::TEXT_PLAIN_UTF_8));
 
@@ -84,7 +84,7 @@ impl<T> Service for Cors<T> where T: Service<Request = Request, Response = Respo
                 .get::<header::AccessControlRequestHeaders>()
                 {
                     None => Vec::new(),
-                    Some(&header::AccessControlRequestHeaders(ref keys)) => keys.to_vec(),
+                    Some(header::AccessControlRequestHeaders(keys)) => keys.to_vec(),
                 };
 
             // Bail if any header isn't in our approved set
@@ -115,7 +115,7 @@ impl<T> Service for Cors<T> where T: Service<Request = Request, Response = Respo
                 }
 
                 // Always set Vary header if CORS is enabled.
-                util::append_header_vary(&mut res.headers_mut(), Ascii::new("Origin".to_string()));
+                util::append_header_vary(res.headers_mut(), Ascii::new("Origin".to_string()));
 
                 res.headers_mut().set(header::AccessControlAllowOrigin::Value(format!("{}", req_origin)));
 
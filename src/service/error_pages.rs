@@ -0,0 +1,153 @@
+// Swaps the body of error responses for a page from the root, e.g. /404.html,
+// keeping the original status code. Sits above Root, Browse, and the other
+// middleware so that their errors get the same treatment.
+//
+// Falls back to the original canned response if the page is missing or isn't a file.
+
+use std::fs::File;
+use std::io;
+
+use futures::{future::ok, Future};
+use futures_cpupool::CpuPool;
+use hyper::{self, header, Method, Request, Response, server::Service};
+
+use access::Access;
+use config::{self, ErrorPages as Config};
+use entity;
+use mime;
+use path;
+
+const CHUNK_SIZE: u64 = 65_536;
+
+#[derive(Debug)]
+pub struct ErrorPages<T> {
+    pool: &'static CpuPool,
+    config: &'static Option<Config>,
+    server: &'static config::Server,
+    next: T,
+}
+
+impl<T> ErrorPages<T> {
+    pub fn new(pool: &'static CpuPool, config: &'static Option<Config>, server: &'static config::Server, next: T) -> Self where T: Service + 'static {
+        ErrorPages { pool, config, server, next }
+    }
+}
+
+impl<T> Service for ErrorPages<T> where T: Service<Request = Request, Response = Response, Error = hyper::Error> + 'static {
+    type Request = T::Request;
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let config = match *self.config {
+            None =>
+                return Box::new(self.next.call(req)),
+            Some(ref config) =>
+                config,
+        };
+
+        let pool = self.pool;
+        let root = &self.server.root;
+        let is_head = *req.method() == Method::Head;
+
+        Box::new(self.next.call(req).and_then(move |res| -> Box<dyn Future<Item = Response, Error = hyper::Error>> {
+            let page = match config.pages.get(&res.status().as_u16()) {
+                None => return Box::new(ok(res)),
+                Some(page) => page,
+            };
+
+            // The page comes from our own config, so it's fine if it's a dotfile, a symlink, or hidden by [access].
+            let page_path = match path::get_entity_path(root, page, path::Dotfiles::Allow, &Access::default()) {
+                Err(_) => return Box::new(ok(res)),
+                Ok(path) => path,
+            };
+
+            // Opening the file blocks, so do it on the pool.
+            let pool2 = pool.clone();
+            Box::new(pool.spawn_fn(move || {
+                let entity = File::open(&page_path).and_then(|file| {
+                    // A directory opens fine but fails once we stream it
+                    if !file.metadata()?.is_file() {
+                        return Err(io::Error::other("not a file"));
+                    }
+                    entity::Entity::new(file, pool2, mime::guess_mime_by_path(&page_path))
+                });
+
+                let entity = match entity {
+                    Ok(entity) => entity,
+                    Err(e) => {
+                        debug!("error page {:?} can't be served ({}), using canned response", page_path, e);
+                        return Ok(res);
+                    }
+                };
+
+                let mut headers = res.headers().clone();
+                headers.remove::<header::ContentEncoding>();
+                headers.remove::<header::TransferEncoding>();
+                headers.set(header::ContentType(entity.content_type().mime.clone()));
+                headers.set(header::ContentLength(entity.len()));
+
+                let res = Response::new()
+                    .with_status(res.status())
+                    .with_headers(headers);
+
+                if is_head {
+                    return Ok(res);
+                }
+
+                Ok(res.with_body(entity.get_range(0..entity.len(), CHUNK_SIZE)))
+            }))
+        }))
+    }
+}
+
+#[cfg(test)]
+struct NotFound;
+
+#[cfg(test)]
+impl Service for NotFound {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = ::futures::future::FutureResult<Response, hyper::Error>;
+
+    fn call(&self, _req: Request) -> Self::Future {
+        ok(::response::not_found())
+    }
+}
+
+#[test]
+fn test_falls_back_when_page_is_not_a_file() {
+    use std::fs;
+    use futures::Stream;
+    use hyper::StatusCode;
+    use leak::Leak;
+    use toml;
+
+    let root = ::std::env::temp_dir().join(format!("hunk-test-error-pages-{}", ::std::process::id()));
+    fs::create_dir_all(root.join("404.html")).unwrap();
+    fs::write(root.join("500.html"), "oops").unwrap();
+    let root = root.canonicalize().unwrap();
+
+    let config = Box::new(Some(toml::from_str::<Config>("404 = \"/404.html\"\n500 = \"/500.html\"").unwrap())).leak();
+    let server = Box::new(config::Server { root: root.clone(), ..Default::default() }).leak();
+
+    // Refused at startup
+    assert!(config.as_ref().unwrap().check(&root).is_err());
+    fs::remove_dir(root.join("404.html")).unwrap();
+    fs::write(root.join("404.html"), "gone").unwrap();
+    assert!(config.as_ref().unwrap().check(&root).is_ok());
+
+    // And if it turns into a directory later, the canned response goes out whole
+    fs::remove_file(root.join("404.html")).unwrap();
+    fs::create_dir(root.join("404.html")).unwrap();
+    let pool = Box::new(CpuPool::new(1)).leak();
+    let service = ErrorPages::new(pool, config, server, NotFound);
+    let res = service.call(Request::new(Method::Get, "/".parse().unwrap())).wait().unwrap();
+    assert_eq!(res.status(), StatusCode::NotFound);
+    let expected = ::response::not_found().body().concat2().wait().unwrap().to_vec();
+    assert_eq!(res.body().concat2().wait().unwrap().to_vec(), expected);
+
+    fs::remove_dir_all(&root).unwrap();
+}
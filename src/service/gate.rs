@@ -19,7 +19,7 @@ impl<T> Service for Gate<T> where T: Service<Request = Request, Response = Respo
     type Request = T::Request;
     type Response = T::Response;
     type Error = T::Error;
-    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
         Box::new(self.next.call(req).map(move |mut res: Response| {
@@ -0,0 +1,139 @@
+// Applies the [[headers]] rules from the config, then the blocks from a Netlify
+// _headers file, to every response including 304s and errors. Every matching
+// rule applies, in order.
+//
+// Sits just under Gate so that it sees the headers of every other middleware.
+
+use std::rc::Rc;
+use std::sync::Arc;
+
+use futures::Future;
+use futures_cpupool::CpuPool;
+use hyper::{self, Request, Response, server::Service};
+
+use config::HeaderRule;
+use netlify::{HeaderBlock, Netlify};
+use path;
+
+#[derive(Debug)]
+pub struct Headers<T> {
+    pool: &'static CpuPool,
+    rules: &'static [HeaderRule],
+    netlify: &'static Netlify,
+    // Also called once a stale _headers has been reloaded
+    next: Rc<T>,
+}
+
+impl<T> Headers<T> {
+    pub fn new(pool: &'static CpuPool, rules: &'static [HeaderRule], netlify: &'static Netlify, next: T) -> Self where T: Service + 'static {
+        Headers { pool, rules, netlify, next: Rc::new(next) }
+    }
+}
+
+impl<T> Service for Headers<T> where T: Service<Request = Request, Response = Response, Error = hyper::Error> + 'static {
+    type Request = T::Request;
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        // _headers is only re-read on the pool
+        if let Some(blocks) = self.netlify.headers.peek() {
+            return with_headers(self.rules, blocks, &*self.next, req);
+        }
+
+        let (rules, netlify, next) = (self.rules, self.netlify, Rc::clone(&self.next));
+
+        Box::new(self.pool.spawn_fn(move || Ok::<_, hyper::Error>(netlify.headers.get()))
+            .and_then(move |blocks| with_headers(rules, blocks, &*next, req)))
+    }
+}
+
+fn with_headers<T>(rules: &'static [HeaderRule], blocks: Arc<Vec<HeaderBlock>>, next: &T, req: Request) -> Box<dyn Future<Item = Response, Error = hyper::Error>>
+    where T: Service<Request = Request, Response = Response, Error = hyper::Error> + 'static
+{
+    if rules.is_empty() && blocks.is_empty() {
+        return Box::new(next.call(req));
+    }
+
+    let path = match path::decode_path(req.path()) {
+        Err(_) =>
+            return Box::new(next.call(req)),
+        Ok(path) =>
+            path,
+    };
+
+    // _headers paths are matched like _redirects, against the raw path
+    let raw_path = req.path().to_string();
+
+    Box::new(next.call(req).map(move |mut res| {
+        apply(rules, &path, res.headers_mut());
+        apply_netlify(&blocks, &raw_path, res.headers_mut());
+        res
+    }))
+}
+
+fn apply(rules: &'static [HeaderRule], path: &str, headers: &mut hyper::Headers) {
+    for rule in rules.iter().filter(|rule| rule.glob.is_match(path)) {
+        for name in &rule.remove {
+            headers.remove_raw(name);
+        }
+        for (name, value) in &rule.set {
+            headers.set_raw(name.as_str(), value.as_str());
+        }
+        for (name, value) in &rule.append {
+            headers.append_raw(name.as_str(), value.as_str());
+        }
+    }
+}
+
+fn apply_netlify(blocks: &[HeaderBlock], path: &str, headers: &mut hyper::Headers) {
+    for block in blocks.iter().filter(|block| block.pattern.is_match(path)) {
+        for (name, value) in &block.headers {
+            headers.set_raw(name.clone(), value.clone());
+        }
+    }
+}
+
+#[test]
+fn test_apply() {
+    use leak::Leak;
+    use toml;
+    use config::Config;
+
+    let config: &'static Config = Box::new(toml::from_str::<Config>(r#"
+        [server]
+
+        [[headers]]
+        glob = "*.html"
+        set = { "X-Robots-Tag" = "noindex", "Content-Language" = "en" }
+
+        [[headers]]
+        glob = "/docs/**"
+        append = { Link = "</docs.css>; rel=preload" }
+        remove = ["X-Powered-By"]
+    "#).unwrap()).leak();
+
+    let headers = |path: &str| {
+        let mut headers = hyper::Headers::new();
+        headers.set_raw("X-Powered-By", "magic");
+        headers.set_raw("Link", "</app.js>; rel=preload");
+        apply(&config.headers, path, &mut headers);
+        headers
+    };
+
+    let h = headers("/index.html");
+    assert_eq!(h.get_raw("X-Robots-Tag").unwrap(), "noindex");
+    assert_eq!(h.get_raw("Content-Language").unwrap(), "en");
+    assert_eq!(h.get_raw("X-Powered-By").unwrap(), "magic");
+    assert_eq!(h.get_raw("Link").unwrap().iter().count(), 1);
+
+    let h = headers("/docs/a.html");
+    assert_eq!(h.get_raw("X-Robots-Tag").unwrap(), "noindex");
+    assert!(h.get_raw("X-Powered-By").is_none());
+    assert_eq!(h.get_raw("Link").unwrap().iter().count(), 2);
+
+    let h = headers("/app.js");
+    assert!(h.get_raw("X-Robots-Tag").is_none());
+    assert_eq!(h.get_raw("X-Powered-By").unwrap(), "magic");
+}
@@ -0,0 +1,83 @@
+// Bearer token auth for [jwt]. Every request needs a valid token, from the
+// Authorization header or the configured cookie, and paths under a rule's
+// prefix also need the rule's claim.
+//
+// No token or a bad one gets a 401. A good token that doesn't have the claim
+// for the path gets a 403.
+
+use futures::{future::ok, Future};
+use hyper::{self, header, Request, Response, server::Service};
+
+use config::Jwt as Config;
+use jwt::{self, Verifier};
+use path;
+use response;
+
+#[derive(Debug)]
+pub struct Jwt<T> {
+    config: &'static Option<Config>,
+    verifier: &'static Option<Verifier>,
+    next: T,
+}
+
+impl<T> Jwt<T> {
+    pub fn new(config: &'static Option<Config>, verifier: &'static Option<Verifier>, next: T) -> Self where T: Service + 'static {
+        Jwt { config, verifier, next }
+    }
+}
+
+impl<T> Service for Jwt<T> where T: Service<Request = Request, Response = Response, Error = hyper::Error> + 'static {
+    type Request = T::Request;
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let (config, verifier) = match (self.config, self.verifier) {
+            (Some(config), Some(verifier)) =>
+                (config, verifier),
+            _ =>
+                return Box::new(self.next.call(req)),
+        };
+
+        let token = match token(&req, config.cookie.as_deref()) {
+            None =>
+                return Box::new(ok(response::unauthorized("Bearer".to_string()))),
+            Some(token) =>
+                token,
+        };
+
+        let claims = match verifier.verify(&token) {
+            Err(e) => {
+                debug!("rejected jwt for {}: {}", req.path(), e);
+                return Box::new(ok(response::unauthorized("Bearer error=\"invalid_token\"".to_string())));
+            }
+            Ok(claims) =>
+                claims,
+        };
+
+        let allowed = match path::decode_path(req.path()).ok().and_then(|path| config.rule(&path)) {
+            None => true,
+            Some(rule) => jwt::allows(&claims, rule),
+        };
+
+        if !allowed {
+            return Box::new(ok(response::forbidden()));
+        }
+
+        Box::new(self.next.call(req))
+    }
+}
+
+// The Authorization header wins over the cookie.
+fn token(req: &Request, cookie: Option<&str>) -> Option<String> {
+    if let Some(header::Authorization(bearer)) = req.headers().get::<header::Authorization<header::Bearer>>() {
+        return Some(bearer.token.clone());
+    }
+
+    let cookie = cookie?;
+    req.headers()
+        .get::<header::Cookie>()
+        .and_then(|cookies| cookies.get(cookie))
+        .map(|token| token.to_string())
+}
@@ -7,6 +7,10 @@ use config::Log as Config;
 
 // TODO: Clean up messy module.
 
+// Set on the response by Auth once a login checks out, and stripped here before
+// the response goes out. The Authorization header alone is whatever the client says.
+pub const REMOTE_USER: &str = "X-Hunk-Remote-User";
+
 #[derive(Debug)]
 pub struct Log<T> {
     peer: Option<SocketAddr>,
@@ -24,12 +28,15 @@ impl<T> Service for Log<T> where T: Service<Request = Request, Response = Respon
     type Request = T::Request;
     type Response = T::Response;
     type Error = T::Error;
-    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
         let config = match *self.config {
             None =>
-                return Box::new(self.next.call(req)),
+                return Box::new(self.next.call(req).map(|mut res| {
+                    res.headers_mut().remove_raw(REMOTE_USER);
+                    res
+                })),
             Some(ref config) =>
                 config
         };
@@ -38,8 +45,9 @@ impl<T> Service for Log<T> where T: Service<Request = Request, Response = Respon
         let req2 = clone_req(&req);
         let peer = self.peer;
 
-        Box::new(self.next.call(req).map(move |res| {
+        Box::new(self.next.call(req).map(move |mut res| {
             log(peer, config, &req2, &res);
+            res.headers_mut().remove_raw(REMOTE_USER);
             res
         }))
     }
@@ -52,26 +60,32 @@ fn clone_req(src: &Request) -> Request {
 }
 
 pub fn log(peer: Option<::std::net::SocketAddr>, opts: &Config, req: &Request, res: &Response) {
+    println!("{}", line(peer, opts, req, res))
+}
+
+fn line(peer: Option<::std::net::SocketAddr>, opts: &Config, req: &Request, res: &Response) -> String {
     let now = Utc::now();
     let remote_port = peer.map(|addr| addr.port());
     let remote_host = peer.map(|addr| addr.ip());
     let method = format!("{}", req.method());
     let path = req.path();
-    let query = req.query().unwrap_or_else(|| "");
+    let query = req.query().unwrap_or("");
     let url = if query.is_empty() {
-        format!("{}", path)
+        path.to_string()
     } else {
         format!("{}?{}", path, query)
     };
     let proto = format!("{}", req.version());
     let status = format!("{}", res.status().as_u16());
+    let remote_user = res.headers().get_raw(REMOTE_USER).and_then(|raw| raw.one()).map(String::from_utf8_lossy);
 
     // TODO: Send actual transferred byte count somehow, not entity length
-    let bytes_tx = if let Some(&header::ContentLength(ref n)) = res.headers().get() { *n } else { 0 };
+    let bytes_tx = if let Some(header::ContentLength(n)) = res.headers().get() { *n } else { 0 };
 
-    let line = opts.format
-        .replace(":remote_host", &remote_host .map(|x| format!("{}", x)) .unwrap_or_else(|| "".to_string()))
-        .replace(":remote_port", &remote_port .map(|x| format!("{}", x)) .unwrap_or_else (|| "".to_string()))
+    opts.format
+        .replace(":remote_host", &remote_host .map(|x| format!("{}", x)) .unwrap_or_default())
+        .replace(":remote_user", remote_user.as_deref().unwrap_or("-"))
+        .replace(":remote_port", &remote_port .map(|x| format!("{}", x)) .unwrap_or_default())
         .replace(":date_clf", &format!("{}", now.format(date_formats::CLF)))
         .replace(":date_iso8601", &format!("{}", now.format(date_formats::ISO_8601_UTC)))
         .replace(":method", &method)
@@ -79,25 +93,83 @@ pub fn log(peer: Option<::std::net::SocketAddr>, opts: &Config, req: &Request, r
         .replace(":url", &url)
         .replace(":proto", &proto)
         .replace(":status", &status)
-        .replace(":bytes_tx", &format!("{}", bytes_tx));
+        .replace(":bytes_tx", &format!("{}", bytes_tx))
 
 //    match opts.output {
 //        Output::Stdout => println!("{}", line),
 //    }
-
-     println!("{}", line)
 }
 
-pub static COMMON_LOG_FORMAT: &'static str =
-    ":remote_host - - [:date_clf] \":method :url :proto\" :status :bytes_tx";
+pub static COMMON_LOG_FORMAT: &str =
+    ":remote_host - :remote_user [:date_clf] \":method :url :proto\" :status :bytes_tx";
 
 #[allow(dead_code)]
 mod date_formats {
-    pub static CLF: &'static str = "%d/%b/%Y:%H:%M:%S %z";
+    pub static CLF: &str = "%d/%b/%Y:%H:%M:%S %z";
     // ISO-8601, e.g. javascript's new Date().toISOString()
-    pub static ISO_8601_UTC: &'static str = "%Y-%m-%dT%H:%M:%S%.3fZ";
+    pub static ISO_8601_UTC: &str = "%Y-%m-%dT%H:%M:%S%.3fZ";
     // When offset from UTC != 0, then the offset is displayed instead of "Z".
-    pub static ISO_8601_OFFSET: &'static str = "%Y-%m-%dT%H:%M:%S%.3f%:z";
+    pub static ISO_8601_OFFSET: &str = "%Y-%m-%dT%H:%M:%S%.3f%:z";
+}
+
+#[test]
+fn test_remote_user_is_verified() {
+    use std::fs;
+    use futures_cpupool::CpuPool;
+    use hyper::Method;
+    use leak::Leak;
+    use toml;
+    use config::Config as HunkConfig;
+    use htpasswd::Htpasswd;
+    use service::{auth::Auth, resolve};
+
+    let root = resolve::scratch_root("log");
+    let htpasswd_path = root.with_file_name(format!("{}.htpasswd", root.file_name().unwrap().to_string_lossy()));
+    // alice:secret
+    fs::write(&htpasswd_path, "alice:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=\n").unwrap();
+
+    let config: &'static HunkConfig = Box::new(toml::from_str::<HunkConfig>(&format!(r#"
+        [server]
+        root = {:?}
+
+        [log]
+        format = ":remote_user"
+
+        [auth]
+        htpasswd = {:?}
+
+        [[auth.rules]]
+        glob = "/Private/**"
+    "#, root, htpasswd_path)).unwrap()).leak();
+
+    let pool = Box::new(CpuPool::new(1)).leak();
+    let htpasswd = Box::new(Some(Htpasswd::new(htpasswd_path.clone()))).leak();
+    let no_log = Box::new(None).leak();
+    let service = Log::new(None, no_log, resolve::stack(config, |next| Auth::new(pool, &config.auth, htpasswd, next)));
+
+    let remote_user = |req_path: &str, username: &str, password: &str| {
+        let mut req = Request::new(Method::Get, req_path.parse().unwrap());
+        req.headers_mut().set(header::Authorization(header::Basic {
+            username: username.to_string(),
+            password: Some(password.to_string()),
+        }));
+        let logged = clone_req(&req);
+        // What Log would print, before it strips the marker
+        let res = service.next.call(req).wait().unwrap();
+        let line = line(None, config.log.as_ref().unwrap(), &logged, &res);
+        // And the marker never leaves
+        let res = service.call(logged).wait().unwrap();
+        assert!(res.headers().get_raw(REMOTE_USER).is_none());
+        line
+    };
+
+    assert_eq!(remote_user("/Private/secret.txt", "alice", "secret"), "alice");
+    assert_eq!(remote_user("/Private/secret.txt", "alice", "wrong"), "-");
+    // [auth] never looked at it
+    assert_eq!(remote_user("/public.txt", "mallory", "anything"), "-");
+
+    fs::remove_dir_all(&root).unwrap();
+    fs::remove_file(&htpasswd_path).unwrap();
 }
 
 //impl Service for Log<Root> {
@@ -5,4 +5,12 @@ pub mod cors;
 pub mod root;
 pub mod compress;
 pub mod browse;
-pub mod gate;
\ No newline at end of file
+pub mod gate;
+pub mod error_pages;
+pub mod cache;
+pub mod headers;
+pub mod redirect;
+pub mod auth;
+pub mod jwt;
+pub mod signed_urls;
+pub mod resolve;
@@ -0,0 +1,235 @@
+// Applies the [[redirect]] and [[rewrite]] rules from the config, then the rules
+// from a Netlify _redirects file.
+//
+// Redirects are answered right here. Rewrites swap out the request path so that
+// Browse and Root resolve the new one, but middleware further up, like [cache]
+// and [[headers]], still see the path the client asked for.
+//
+// The first matching redirect wins, then the first matching rewrite.
+//
+// Anything that touches the disk, like reloading _redirects or checking for a file
+// that shadows an unforced rule, runs on the pool.
+
+use std::rc::Rc;
+
+use futures::{future::ok, Future};
+use futures_cpupool::CpuPool;
+use hyper::{self, Request, Response, StatusCode, Uri, server::Service};
+
+use access::Access;
+use lookup::Lookup;
+use config;
+use netlify::{self, Netlify};
+use path;
+use response;
+
+#[derive(Debug)]
+pub struct Redirect<T> {
+    pool: &'static CpuPool,
+    redirects: &'static [config::Redirect],
+    rewrites: &'static [config::Rewrite],
+    server: &'static config::Server,
+    netlify: &'static Netlify,
+    access: &'static Access,
+    lookup: &'static Lookup,
+    // Also called once the pool has picked a route
+    next: Rc<T>,
+}
+
+impl<T> Redirect<T> {
+    // Takes its config pieces one by one like the rest of the pipe!
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        pool: &'static CpuPool,
+        redirects: &'static [config::Redirect],
+        rewrites: &'static [config::Rewrite],
+        server: &'static config::Server,
+        netlify: &'static Netlify,
+        access: &'static Access,
+        lookup: &'static Lookup,
+        next: T,
+    ) -> Self where T: Service + 'static {
+        Redirect { pool, redirects, rewrites, server, netlify, access, lookup, next: Rc::new(next) }
+    }
+}
+
+// What to do with a request once the rules have had their say
+enum Route {
+    Redirect(StatusCode, String),
+    Rewrite(String),
+    // Serve the target with a 404 status
+    NotFound(String),
+    Pass,
+}
+
+impl<T> Service for Redirect<T> where T: Service<Request = Request, Response = Response, Error = hyper::Error> + 'static {
+    type Request = T::Request;
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        if let Some(route) = configured(self.redirects, self.rewrites, req.path(), req.query()) {
+            return forward(&*self.next, req, route);
+        }
+
+        // Answer from the _redirects we already have unless it's due for a reload
+        // or a rule needs a stat(), in which case it's off to the pool.
+        let cached = self.netlify.redirects.peek()
+            .and_then(|redirects| route(&redirects, req.path(), req.query(), &mut || None));
+
+        if let Some(route) = cached {
+            return forward(&*self.next, req, route);
+        }
+
+        let (netlify, server, access, lookup) = (self.netlify, self.server, self.access, self.lookup);
+        let (req_path, query) = (req.path().to_string(), req.query().map(|query| query.to_string()));
+        let next = Rc::clone(&self.next);
+
+        Box::new(self.pool.spawn_fn(move || {
+            Ok::<_, hyper::Error>(netlify_route(netlify, server, access, lookup, &req_path, query.as_deref()))
+        }).and_then(move |route| forward(&*next, req, route)))
+    }
+}
+
+// The first matching [[redirect]], else the first matching [[rewrite]].
+fn configured(redirects: &[config::Redirect], rewrites: &[config::Rewrite], req_path: &str, query: Option<&str>) -> Option<Route> {
+    for redirect in redirects {
+        if let Some(location) = redirect.rule.apply(req_path, query) {
+            return Some(Route::Redirect(redirect.status, location));
+        }
+    }
+
+    rewrites
+        .iter()
+        .filter_map(|rewrite| rewrite.rule.apply(req_path, query))
+        .next()
+        .map(Route::Rewrite)
+}
+
+// route() with a fresh _redirects and a real stat() for the shadow check. Blocks.
+fn netlify_route(netlify: &Netlify, server: &config::Server, access: &Access, lookup: &Lookup, req_path: &str, query: Option<&str>) -> Route {
+    let route = route(&netlify.redirects.get(), req_path, query, &mut || {
+        match path::resolve(server, req_path, query, access, lookup) {
+            Ok(path::Resolved::File(_)) => Some(true),
+            _ => Some(false),
+        }
+    });
+    route.unwrap_or(Route::Pass)
+}
+
+// The path that Redirect hands on for req_path, or None if it isn't rewritten.
+// `hunk sign` has to sign this since [signed_urls] checks the rewritten path.
+pub fn rewritten(
+    redirects: &[config::Redirect],
+    rewrites: &[config::Rewrite],
+    server: &config::Server,
+    netlify: &Netlify,
+    access: &Access,
+    lookup: &Lookup,
+    req_path: &str,
+) -> Option<String> {
+    let route = configured(redirects, rewrites, req_path, None)
+        .unwrap_or_else(|| netlify_route(netlify, server, access, lookup, req_path, None));
+
+    match route {
+        Route::Rewrite(target) | Route::NotFound(target) =>
+            target.split('?').next().map(|path| path.to_string()),
+        Route::Redirect(..) | Route::Pass =>
+            None,
+    }
+}
+
+// The first matching _redirects rule.
+//
+// Unforced rules don't apply when there's a file at the requested path, which
+// is_file says. Returns None when is_file can't tell, i.e. off the pool.
+fn route(redirects: &[netlify::Redirect], req_path: &str, query: Option<&str>, is_file: &mut dyn FnMut() -> Option<bool>) -> Option<Route> {
+    // Only stat the file if a rule matches.
+    let mut shadowed = None;
+
+    for redirect in redirects {
+        let target = match redirect.rule.apply(req_path, query) {
+            None => continue,
+            Some(target) => target,
+        };
+
+        if !redirect.force {
+            if shadowed.is_none() {
+                shadowed = Some(is_file()?);
+            }
+            if shadowed == Some(true) {
+                return Some(Route::Pass);
+            }
+        }
+
+        return Some(match redirect.action {
+            netlify::Action::Redirect(status) => Route::Redirect(status, target),
+            netlify::Action::Rewrite => Route::Rewrite(target),
+            netlify::Action::NotFound => Route::NotFound(target),
+        });
+    }
+
+    Some(Route::Pass)
+}
+
+fn forward<T>(next: &T, mut req: Request, route: Route) -> Box<dyn Future<Item = Response, Error = hyper::Error>>
+    where T: Service<Request = Request, Response = Response, Error = hyper::Error> + 'static
+{
+    let (target, not_found) = match route {
+        Route::Redirect(status, location) =>
+            return Box::new(ok(response::redirect(status, location))),
+        Route::Rewrite(target) =>
+            (target, false),
+        Route::NotFound(target) =>
+            (target, true),
+        Route::Pass =>
+            return Box::new(next.call(req)),
+    };
+
+    match target.parse::<Uri>() {
+        Ok(uri) => {
+            debug!("rewrote {} to {}", req.uri(), uri);
+            req.set_uri(uri);
+        }
+        Err(e) =>
+            warn!("could not rewrite {} to {:?}: {}", req.uri(), target, e),
+    }
+
+    if !not_found {
+        return Box::new(next.call(req));
+    }
+
+    Box::new(next.call(req).map(|mut res| {
+        if res.status().is_success() {
+            res.set_status(StatusCode::NotFound);
+        }
+        res
+    }))
+}
+
+#[test]
+fn test_route() {
+    let redirects = netlify::parse_redirects("
+        /old        /new        301!
+        /app/*      /index.html 200
+    ");
+
+    let route_with = |req_path, is_file: Option<bool>| {
+        match route(&redirects, req_path, None, &mut || is_file) {
+            None => "stat",
+            Some(Route::Redirect(..)) => "redirect",
+            Some(Route::Rewrite(_)) => "rewrite",
+            Some(Route::NotFound(_)) => "not found",
+            Some(Route::Pass) => "pass",
+        }
+    };
+
+    // Forced rules and misses never need the disk
+    assert_eq!(route_with("/old", None), "redirect");
+    assert_eq!(route_with("/other", None), "pass");
+    // Unforced rules do
+    assert_eq!(route_with("/app/x", None), "stat");
+    assert_eq!(route_with("/app/x", Some(false)), "rewrite");
+    assert_eq!(route_with("/app/x", Some(true)), "pass");
+}
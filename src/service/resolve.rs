@@ -0,0 +1,245 @@
+// Swaps the request path for the one Browse and Root will actually serve, e.g.
+// /Private/index.html for /PRIVATE/, so that [auth], [jwt], and [signed_urls]
+// below it judge the file itself. Otherwise the lookup fallback, a directory
+// index, or try_files would serve a protected file under a path no rule covers.
+//
+// Sits under Redirect so that rewrites are settled too. Only runs when one of
+// those sections is configured since it costs a trip to the pool.
+
+use std::rc::Rc;
+
+use futures::{future::ok, Future};
+use futures_cpupool::CpuPool;
+use hyper::{self, Request, Response, Uri, server::Service};
+
+use access::Access;
+use config;
+use lookup::Lookup;
+use path;
+use response;
+
+#[derive(Debug)]
+pub struct Resolve<T> {
+    pool: &'static CpuPool,
+    // Whether any path policy is configured
+    guarded: bool,
+    server: &'static config::Server,
+    browse: &'static Option<config::Browse>,
+    access: &'static Access,
+    lookup: &'static Lookup,
+    // Also called with the settled path
+    next: Rc<T>,
+}
+
+impl<T> Resolve<T> {
+    pub fn new(
+        pool: &'static CpuPool,
+        guarded: bool,
+        server: &'static config::Server,
+        browse: &'static Option<config::Browse>,
+        access: &'static Access,
+        lookup: &'static Lookup,
+        next: T,
+    ) -> Self where T: Service + 'static {
+        Resolve { pool, guarded, server, browse, access, lookup, next: Rc::new(next) }
+    }
+}
+
+impl<T> Service for Resolve<T> where T: Service<Request = Request, Response = Response, Error = hyper::Error> + 'static {
+    type Request = T::Request;
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, mut req: Self::Request) -> Self::Future {
+        if !self.guarded {
+            return Box::new(self.next.call(req));
+        }
+
+        let (server, access, lookup, browse) = (self.server, self.access, self.lookup, self.browse.is_some());
+        let req_path = req.path().to_string();
+        let next = Rc::clone(&self.next);
+
+        Box::new(self.pool.spawn_fn(move || {
+            Ok::<_, hyper::Error>(path::settle(server, &req_path, access, lookup, browse))
+        }).and_then(move |settled| -> Box<dyn Future<Item = Response, Error = hyper::Error>> {
+            if let Some(settled) = settled {
+                let target = match req.query() {
+                    Some(query) => format!("{}?{}", settled, query),
+                    None => settled,
+                };
+                match target.parse::<Uri>() {
+                    Ok(uri) => {
+                        if uri != *req.uri() {
+                            debug!("settled {} on {}", req.uri(), uri);
+                        }
+                        req.set_uri(uri);
+                    }
+                    // Don't let the policies judge a path we won't serve
+                    Err(e) => {
+                        warn!("could not settle {} on {:?}: {}", req.uri(), target, e);
+                        return Box::new(ok(response::internal_server_error()));
+                    }
+                }
+            }
+            Box::new(next.call(req))
+        }))
+    }
+}
+
+// A scratch root with a /Private folder, for testing the policies under Resolve.
+#[cfg(test)]
+pub fn scratch_root(name: &str) -> ::std::path::PathBuf {
+    use std::fs;
+
+    let root = ::std::env::temp_dir().join(format!("hunk-test-{}-{}", name, ::std::process::id()));
+    fs::create_dir_all(root.join("Private")).unwrap();
+    fs::write(root.join("Private/secret.txt"), "secret").unwrap();
+    fs::write(root.join("public.txt"), "public").unwrap();
+    root.canonicalize().unwrap()
+}
+
+// Redirect over Resolve over the policy over Root, the way serve() stacks them.
+#[cfg(test)]
+pub fn stack<P, F>(config: &'static config::Config, policy: F) -> ::service::redirect::Redirect<Resolve<P>>
+    where F: FnOnce(::service::root::Root) -> P,
+          P: Service<Request = Request, Response = Response, Error = hyper::Error> + 'static,
+{
+    use leak::Leak;
+    use etag::ETagger;
+    use netlify::Netlify;
+    use service::{redirect::Redirect, root::Root};
+
+    let pool = Box::new(CpuPool::new(1)).leak();
+    let file_cache = Box::new(None).leak();
+    let etagger = Box::new(ETagger::new(Default::default())).leak();
+    let netlify = Box::new(Netlify::new(&config.server.root)).leak();
+    let access = Box::new(Access::new(&config.access, &config.server.root)).leak();
+    let lookup = Box::new(Lookup::new(&config.server)).leak();
+
+    let root = Root::new(pool, &config.server, &config.compress, file_cache, etagger, access, lookup);
+    let resolve = Resolve::new(pool, true, &config.server, &config.browse, access, lookup, policy(root));
+    Redirect::new(pool, &config.redirect, &config.rewrite, &config.server, netlify, access, lookup, resolve)
+}
+
+// Each guard in turn, over a path it protects that's only reached through the
+// case-insensitive lookup or a rewrite.
+#[test]
+fn test_guards_judge_the_served_path() {
+    use std::fs;
+    use futures::Stream;
+    use hyper::{header, Method, StatusCode};
+    use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+    use leak::Leak;
+    use toml;
+    use htpasswd::Htpasswd;
+    use jwt::Verifier;
+    use signed_url::{self, Signer};
+    use service::{auth::Auth, jwt::Jwt, signed_urls::SignedUrls};
+
+    let root = scratch_root("guards");
+    let secrets = root.with_extension("secrets");
+    fs::create_dir_all(&secrets).unwrap();
+    // alice:secret
+    fs::write(secrets.join("htpasswd"), "alice:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=\n").unwrap();
+    fs::write(secrets.join("jwt.key"), "s3cret").unwrap();
+    fs::write(secrets.join("signed_urls.key"), "0123456789abcdef").unwrap();
+
+    let config: &'static config::Config = Box::new(toml::from_str::<config::Config>(&format!(r#"
+        [server]
+        root = {:?}
+        case_insensitive = true
+
+        [[rewrite]]
+        from = "/download"
+        to = "/Private/secret.txt"
+
+        [auth]
+        htpasswd = {:?}
+
+        [[auth.rules]]
+        glob = "/Private/**"
+
+        [jwt]
+        algorithm = "HS256"
+        key = {:?}
+
+        [[jwt.rules]]
+        prefix = "/Private/"
+        claim = "groups"
+        values = ["staff"]
+
+        [signed_urls]
+        key = {:?}
+        prefixes = ["/Private/"]
+    "#, root, secrets.join("htpasswd"), secrets.join("jwt.key"), secrets.join("signed_urls.key"))).unwrap()).leak();
+
+    let pool = Box::new(CpuPool::new(1)).leak();
+    let htpasswd = Box::new(Some(Htpasswd::new(secrets.join("htpasswd")))).leak();
+    let verifier = Box::new(Some(Verifier::new(config.jwt.as_ref().unwrap()).unwrap())).leak();
+    let signer = Box::new(Some(Signer::new(config.signed_urls.as_ref().unwrap()).unwrap())).leak();
+
+    let token = |groups: &[&str]| {
+        let claims = json!({ "sub": "alice", "groups": groups, "exp": signed_url::now() + 60 });
+        encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(b"s3cret")).unwrap()
+    };
+    let (guest, staff) = (token(&[]), token(&["staff"]));
+    let query = signer.as_ref().unwrap().sign("/Private/secret.txt", signed_url::now() + 60, None);
+
+    // Sends the request with or without good credentials, and returns the status and body
+    type Call = Box<dyn Fn(&str, bool) -> (StatusCode, String)>;
+
+    fn respond<S>(service: &S, req: Request) -> (StatusCode, String)
+        where S: Service<Request = Request, Response = Response, Error = hyper::Error>
+    {
+        let res = service.call(req).wait().unwrap();
+        let status = res.status();
+        (status, String::from_utf8(res.body().concat2().wait().unwrap().to_vec()).unwrap())
+    }
+
+    let auth = stack(config, |next| Auth::new(pool, &config.auth, htpasswd, next));
+    let jwt = stack(config, |next| Jwt::new(&config.jwt, verifier, next));
+    let signed_urls = stack(config, |next| SignedUrls::new(None, &config.signed_urls, signer, next));
+
+    let guards: Vec<(&str, StatusCode, Call)> = vec![
+        ("auth", StatusCode::Unauthorized, Box::new(move |url: &str, good: bool| {
+            let mut req = Request::new(Method::Get, url.parse().unwrap());
+            req.headers_mut().set(header::Authorization(header::Basic {
+                username: "alice".to_string(),
+                password: Some(if good { "secret" } else { "wrong" }.to_string()),
+            }));
+            respond(&auth, req)
+        })),
+        ("jwt", StatusCode::Forbidden, Box::new(move |url: &str, good: bool| {
+            let mut req = Request::new(Method::Get, url.parse().unwrap());
+            let token = if good { staff.clone() } else { guest.clone() };
+            req.headers_mut().set(header::Authorization(header::Bearer { token }));
+            respond(&jwt, req)
+        })),
+        ("signed_urls", StatusCode::Forbidden, Box::new(move |url: &str, good: bool| {
+            let url = if good { format!("{}?{}", url, query) } else { url.to_string() };
+            respond(&signed_urls, Request::new(Method::Get, url.parse().unwrap()))
+        })),
+    ];
+
+    for (name, denied, call) in &guards {
+        assert_eq!(call("/public.txt", false), (StatusCode::Ok, "public".to_string()), "{}", name);
+
+        // As named on disk, found by the case-insensitive lookup, and rewritten onto it
+        for url in &["/Private/secret.txt", "/PRIVATE/SECRET.TXT", "/download"] {
+            assert_eq!(call(url, false).0, *denied, "{} {}", name, url);
+            assert_eq!(call(url, true), (StatusCode::Ok, "secret".to_string()), "{} {}", name, url);
+        }
+    }
+
+    // hunk sign signs the served path, whether it was case folded or rewritten
+    let (_, _, signed_urls) = &guards[2];
+    for req_path in &["/PRIVATE/secret.txt", "/download"] {
+        let url = ::sign_url(config, req_path, ::std::time::Duration::from_secs(60), None).unwrap();
+        assert!(url.starts_with("/Private/secret.txt?"), "{}", url);
+        assert_eq!(signed_urls(&url, false), (StatusCode::Ok, "secret".to_string()), "{}", req_path);
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+    fs::remove_dir_all(&secrets).unwrap();
+}
@@ -1,29 +1,58 @@
 use futures_cpupool::CpuPool;
-use futures::{Future};
+use futures::{future::ok, Future};
 use hyper::server::{Request, Response, Service};
 use hyper::{self, Body, Method, header, StatusCode};
 
 use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use unicase::Ascii;
 
 use response;
 use entity;
 use mime;
 use range;
-use negotiation;
+use precondition;
 use config;
 use path;
+use multipart;
+use precompressed;
+use util;
+use negotiation;
+use compress::Encoder;
+use file_cache::FileCache;
+use etag::{self, ETagger};
+use access::Access;
+use service::compress;
+use lookup::Lookup;
 
 const CHUNK_SIZE: u64 = 65_536;
 
-#[derive(Debug)]
+// Only holds &'static references, so it's cheap to copy onto the pool.
+#[derive(Clone, Copy)]
 pub struct Root {
     pool: &'static CpuPool,
     config: &'static config::Server,
+    compress: &'static Option<config::Compress>,
+    cache: &'static Option<FileCache>,
+    etagger: &'static ETagger,
+    access: &'static Access,
+    lookup: &'static Lookup,
 }
 
 impl Root {
-    pub fn new(pool: &'static CpuPool, config: &'static config::Server) -> Self {
-        Root { pool, config }
+    pub fn new(
+        pool: &'static CpuPool,
+        config: &'static config::Server,
+        compress: &'static Option<config::Compress>,
+        cache: &'static Option<FileCache>,
+        etagger: &'static ETagger,
+        access: &'static Access,
+        lookup: &'static Lookup,
+    ) -> Self {
+        Root { pool, config, compress, cache, etagger, access, lookup }
     }
 }
 
@@ -31,27 +60,72 @@ impl Service for Root {
     type Request = Request;
     type Response = Response<Body>;
     type Error = hyper::Error;
-    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, req: Request) -> Self::Future {
-        let pool = self.pool.clone();
-        let config = self.config;
+        let root = *self;
+
+        // A hit costs a single stat(), so it's answered right here without waiting on the pool.
+        if let Some(ref cache) = *self.cache {
+            if *req.method() == Method::Get || *req.method() == Method::Head {
+                let cached = handle_cached(&root, cache, &req);
+                cache.count(cached.is_some());
+                if let Some(res) = cached {
+                    return Box::new(ok(res));
+                }
+            }
+        }
 
         Box::new(self.pool.spawn_fn(move || {
-            let res = handle_request(&pool, config, &req);
+            let res = handle_request(&root, &req);
             Ok(res)
         }))
     }
 }
 
-fn handle_request(pool: &CpuPool, config: &'static config::Server, req: &Request) -> Response<Body> {
+fn handle_cached(root: &Root, cache: &FileCache, req: &Request) -> Option<Response<Body>> {
+    let cached = cache.get(req.path())?;
+
+    let sidecars: Vec<Encoder> = cached.sidecars.iter().map(|&(encoder, _)| encoder).collect();
+    let encoder = negotiation::negotiate_encoding(req.headers().get::<header::AcceptEncoding>(), &sidecars)
+        .unwrap_or(None);
+
+    let variant = match encoder {
+        Some(encoder) => cached.sidecars.iter().find(|&&(e, _)| e == encoder).map(|(_, variant)| variant)?,
+        None => &cached.original,
+    };
+
+    let entity = entity::Entity::from_memory(
+        Arc::clone(&variant.bytes),
+        variant.validator.clone(),
+        root.pool.clone(),
+        mime::guess_mime_by_path(&cached.path),
+    );
+
+    Some(respond(req, root.compress, variant.etag.clone(), entity, encoder, !sidecars.is_empty()))
+}
+
+fn handle_request(root: &Root, req: &Request) -> Response<Body> {
+    let config = root.config;
+    let pool = root.pool;
+    let etagger = root.etagger;
+
     if *req.method() != Method::Get && *req.method() != Method::Head && *req.method() != Method::Options {
         return response::method_not_allowed();
     }
 
-    let entity_path = match path::get_entity_path(&config.root, req.path()) {
-        None => return response::not_found(),
-        Some(path) => path,
+    let entity_path = match path::resolve(config, req.path(), req.query(), root.access, root.lookup) {
+        Ok(path::Resolved::File(path)) => path,
+        Ok(path::Resolved::Redirect(location)) => return response::moved_permanently(location),
+        Err(path::PathError::Malformed) => return response::bad_request(),
+        Err(path::PathError::Forbidden) => return response::forbidden(),
+        // Only service files, but give the try_files chain a shot before we 404.
+        Ok(path::Resolved::Directory(_)) | Err(path::PathError::NotFound) => {
+            match path::try_files(config, req.path(), root.access, root.lookup) {
+                Some(path) => path,
+                None => return response::not_found(),
+            }
+        }
     };
 
     let file = match File::open(&entity_path) {
@@ -59,12 +133,18 @@ fn handle_request(pool: &CpuPool, config: &'static config::Server, req: &Request
         Ok(file) => file,
     };
 
-    // Only service files
-    if let Ok(false) = file.metadata().map(|meta| meta.is_file()) {
-        return response::not_found()
-    }
+    // Serve a precompressed sidecar, e.g. app.js.br, in place of the file if the client accepts it.
+    // Content-Type still comes from the original file's extension.
+    let (sidecars, sidecar) = match *root.compress {
+        Some(ref compress) if compress.precompressed =>
+            precompressed::find(&entity_path, &compress.encoders, req.headers().get::<header::AcceptEncoding>()),
+        _ =>
+            (Vec::new(), None),
+    };
+
+    let has_sidecar = !sidecars.is_empty();
 
-    let entity = match entity::Entity::new(
+    let original = match entity::Entity::new(
         file,
         pool.clone(),
         mime::guess_mime_by_path(&entity_path),
@@ -73,76 +153,184 @@ fn handle_request(pool: &CpuPool, config: &'static config::Server, req: &Request
         Ok(entity) => entity,
     };
 
-    let mut res = Response::new();
+    let sidecar = match sidecar {
+        Some(precompressed::Sidecar { file, encoder }) =>
+            entity::Entity::new(file, pool.clone(), mime::guess_mime_by_path(&entity_path))
+                .ok()
+                .map(|entity| (encoder, precompressed::sidecar_path(&entity_path, encoder).unwrap(), entity))
+                // The sidecar is its own file, so it could be a link out of the root even if the original isn't.
+                .filter(|(_, sidecar_path, _)| path::check_symlinks(&config.root, sidecar_path, config.symlinks).is_ok()),
+        None =>
+            None,
+    };
 
-    // HANDLE CACHING HEADERS
+    let res = match sidecar {
+        Some((encoder, ref sidecar_path, ref entity)) =>
+            respond(req, root.compress, etag(etagger, sidecar_path, entity, Some(encoder)), entity.clone(), Some(encoder), has_sidecar),
+        None =>
+            respond(req, root.compress, etag(etagger, &entity_path, &original, None), original.clone(), None, has_sidecar),
+    };
+
+    // Cache miss, so remember the file and all of its sidecars for next time if they're small.
+    // Only for a full GET, since a HEAD, a 304, or a range doesn't need the whole body.
+    if let Some(ref cache) = *root.cache {
+        if *req.method() == Method::Get && res.status() == StatusCode::Ok {
+            let sidecars: Option<Vec<_>> = sidecars.iter().map(|&encoder| {
+                let sidecar_path = precompressed::sidecar_path(&entity_path, encoder)?;
+                path::check_symlinks(&config.root, &sidecar_path, config.symlinks).ok()?;
+                let file = File::open(&sidecar_path).ok()?;
+                let entity = entity::Entity::new(file, pool.clone(), mime::guess_mime_by_path(&entity_path)).ok()?;
+                let etag = etag(etagger, &sidecar_path, &entity, Some(encoder));
+                Some((encoder, entity, etag))
+            }).collect();
+
+            if let Some(sidecars) = sidecars {
+                cache.fill(
+                    req.path(),
+                    &entity_path,
+                    (&original, etag(etagger, &entity_path, &original, None)),
+                    sidecars.iter().map(|&(encoder, ref entity, ref etag)| (encoder, entity, etag.clone())).collect(),
+                );
+            }
+        }
+    }
+
+    res
+}
+
+// Sidecars are their own representation, so make sure their tag can never
+// collide with the original's.
+fn etag(etagger: &ETagger, disk_path: &Path, entity: &entity::Entity, encoder: Option<Encoder>) -> Option<header::EntityTag> {
+    etagger.etag(disk_path, entity).map(|etag| match encoder {
+        Some(encoder) => header::EntityTag::strong(format!("{}-{}", etag.tag(), encoder.name())),
+        None => etag,
+    })
+}
 
-    let entity_etag = entity.etag(&entity::ETagKind::Strong);
+// Responds with the entity, handling conditional and range requests.
+//
+// - compress: so that a 304 matches the 200 that Compress would have sent
+// - entity_etag: from etag(), or from the file cache
+// - encoder: the entity is a precompressed sidecar with this encoding
+// - has_sidecar: the file has precompressed sidecars at all, so the response varies
+fn respond(
+    req: &Request,
+    compress: &Option<config::Compress>,
+    entity_etag: Option<header::EntityTag>,
+    entity: entity::Entity,
+    encoder: Option<Encoder>,
+    has_sidecar: bool,
+) -> Response<Body> {
+
+    // COMMON HEADERS
+
+    let mut res = Response::new();
+    if let Some(ref etag) = entity_etag {
+        res.headers_mut().set(header::ETag(etag.clone()));
+    }
+    res.headers_mut().set(header::AcceptRanges(vec![header::RangeUnit::Bytes]));
+    res.headers_mut().set(header::LastModified(entity.last_modified()));
+    res.headers_mut().set(header::ContentType(entity.content_type().mime.clone()));
 
-    if is_not_modified(&entity, req, &entity_etag) {
-        return response::not_modified(entity_etag);
+    if let Some(encoder) = encoder {
+        res.headers_mut().set(header::ContentEncoding(vec![encoder.header()]));
     }
 
-    if is_precondition_failed(&entity, req, &entity_etag) {
-        return response::precondition_failed();
+    // Our response depends on Accept-Encoding whenever there's a sidecar to choose,
+    // even if this client didn't get it.
+    if has_sidecar {
+        util::append_header_vary(res.headers_mut(), Ascii::new("Accept-Encoding".to_string()));
+    }
+
+    // HANDLE CACHING HEADERS
+
+    match precondition::evaluate(req.method(), req.headers(), entity_etag.as_ref(), entity.last_modified()) {
+        precondition::Precondition::Pass =>
+            {},
+        precondition::Precondition::NotModified => {
+            let mut res = response::not_modified(res.headers());
+            // The client may be holding the body that Compress would have gzipped under
+            // a weak tag. Don't hand it back the strong one.
+            if let (Some(compress), None) = (compress, encoder) {
+                if compress::is_compressible(compress, &entity.content_type().mime, entity.len()) {
+                    util::append_header_vary(res.headers_mut(), Ascii::new("Accept-Encoding".to_string()));
+                    if let Ok(Some(_)) = negotiation::negotiate_encoding(req.headers().get::<header::AcceptEncoding>(), &compress.encoders) {
+                        etag::weaken(res.headers_mut());
+                    }
+                }
+            }
+            return res;
+        }
+        precondition::Precondition::Failed =>
+            return response::precondition_failed(),
     }
 
     // PARSE RANGE HEADER
     // - Comes after evaluating precondition headers.
     //   <https://tools.ietf.org/html/rfc7233#section-3.1>
-
-    let range = range::parse_range_header(
-        req.headers().has::<header::Range>(),
-        req.headers().get::<header::Range>(),
-        entity.len(),
-    );
+    // - If-Range lets a client resume a download only if the entity hasn't changed
+    //   since, otherwise it gets the full entity instead of a chimera of two versions.
+    //   <https://tools.ietf.org/html/rfc7233#section-3.2>
+
+    let range = if range::if_range(
+        req.headers().has::<header::IfRange>(),
+        req.headers().get::<header::IfRange>(),
+        entity_etag.as_ref(),
+        entity.last_modified(),
+        header::HttpDate::from(SystemTime::now()),
+    ) {
+        range::parse_range_header(
+            req.headers().has::<header::Range>(),
+            req.headers().get::<header::Range>(),
+            entity.len(),
+        )
+    } else {
+        range::RequestedRange::None
+    };
 
     // Client provided a bad range
     if let range::RequestedRange::NotSatisfiable = range {
         return response::invalid_range(entity.len());
     };
 
-    // COMMON HEADERS
-
-    res.headers_mut().set(header::ETag(entity_etag));
-    res.headers_mut().set(header::AcceptRanges(vec![header::RangeUnit::Bytes]));
-    res.headers_mut().set(header::LastModified(entity.last_modified()));
-    res.headers_mut().set(header::ContentType(entity.content_type().mime.clone()));
-
     // More about Content-Length: <https://tools.ietf.org/html/rfc2616#section-4.4>
     // - Represents length *after* transfer-encoding.
     // - Don't set Content-Length if Transfer-Encoding != 'identity'
     res.headers_mut().set(header::ContentLength(entity.len()));
 
-    // Only set max-age if it's configured at all.
-//    if let Some(max_age) = ctx.config.cache.as_ref().map(|opts| opts.max_age) {
-//        res.headers_mut().set(header::CacheControl(vec![
-//            header::CacheDirective::Public,
-//            header::CacheDirective::MaxAge(max_age.as_secs() as u32),
-//        ]));
-//    }
-
     // Start streaming the file.
 
-    let body = {
-        let range = match range {
-            range::RequestedRange::Satisfiable(mut range) => {
+    let body = match range {
+        range::RequestedRange::Satisfiable(mut range) => {
+            res.set_status(StatusCode::PartialContent);
+            res.headers_mut()
+                .set(header::ContentRange(header::ContentRangeSpec::Bytes {
+                    range: Some((range.start, range.end)),
+                    instance_length: Some(entity.len()),
+                }));
+
+            // NOTE: Range header is end-inclusive but std::ops::Range is end-exclusive.
+            range.end += 1;
+
+            res.headers_mut().set(header::ContentLength(range.end - range.start));
+
+            entity.get_range(range, CHUNK_SIZE)
+        }
+        range::RequestedRange::Multiple(ranges) => {
+            let parts = multipart::ByteRanges::new(&entity, &ranges);
+
+            // The multipart framing can outweigh the savings, e.g. lots of tiny ranges
+            // over a small file, in which case we just send the whole thing.
+            if parts.len() >= entity.len() {
+                entity.get_range(0..entity.len(), CHUNK_SIZE)
+            } else {
                 res.set_status(StatusCode::PartialContent);
-                res.headers_mut()
-                    .set(header::ContentRange(header::ContentRangeSpec::Bytes {
-                        range: Some((range.start, range.end)),
-                        instance_length: Some(entity.len()),
-                    }));
-
-                // NOTE: Range header is end-inclusive but std::ops::Range is end-exclusive.
-                range.end += 1;
-
-                range
+                res.headers_mut().set_raw("Content-Type", parts.content_type());
+                res.headers_mut().set(header::ContentLength(parts.len()));
+                parts.into_body(&entity, CHUNK_SIZE)
             }
-            _ => 0..entity.len(),
-        };
-
-        entity.get_range(range, CHUNK_SIZE)
+        }
+        _ => entity.get_range(0..entity.len(), CHUNK_SIZE),
     };
 
     // For HEAD requests, we do all the work except sending the body.
@@ -153,30 +341,147 @@ fn handle_request(pool: &CpuPool, config: &'static config::Server, req: &Request
     res.with_body(body)
 }
 
-fn is_not_modified(
-    entity: &entity::Entity,
-    req: &Request,
-    entity_etag: &header::EntityTag,
-) -> bool {
-    if !negotiation::none_match(req.headers().get::<header::IfNoneMatch>(), entity_etag) {
-        true
-    } else if let Some(&header::IfModifiedSince(since)) = req.headers().get() {
-        entity.last_modified() <= since
-    } else {
-        false
+// Polls the future once without blocking, so a future that's waiting on the pool comes back None.
+#[cfg(test)]
+fn poll_once<F: Future>(future: F) -> Option<F::Item> where F::Error: ::std::fmt::Debug {
+    use futures::{executor, Async};
+
+    struct Noop;
+    impl executor::Notify for Noop {
+        fn notify(&self, _id: usize) {}
     }
-}
 
-fn is_precondition_failed(
-    entity: &entity::Entity,
-    req: &Request,
-    entity_etag: &header::EntityTag,
-) -> bool {
-    if !negotiation::any_match(req.headers().get::<header::IfMatch>(), entity_etag) {
-        true
-    } else if let Some(&header::IfUnmodifiedSince(since)) = req.headers().get() {
-        entity.last_modified() > since
-    } else {
-        false
+    match executor::spawn(future).poll_future_notify(&Arc::new(Noop), 0).unwrap() {
+        Async::Ready(item) => Some(item),
+        Async::NotReady => None,
     }
 }
+
+#[test]
+fn test_file_cache() {
+    use std::fs;
+    use futures::Stream;
+    use leak::Leak;
+    use toml;
+    use config::Config;
+
+    let root = ::std::env::temp_dir().join(format!("hunk-test-root-file-cache-{}", ::std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    let root = root.canonicalize().unwrap();
+    fs::write(root.join("app.js"), "plain").unwrap();
+
+    let config: &'static Config = Box::new(toml::from_str::<Config>(&format!(r#"
+        [server]
+        root = {:?}
+
+        [compress]
+        precompressed = true
+
+        [file_cache]
+    "#, root)).unwrap()).leak();
+
+    let pool = Box::new(CpuPool::new(1)).leak();
+    let cache = Box::new(config.file_cache.clone().map(FileCache::new)).leak();
+    let etagger = Box::new(ETagger::new(Default::default())).leak();
+    let access = Box::new(Access::default()).leak();
+    let lookup = Box::new(Lookup::default()).leak();
+    let service = Root::new(pool, &config.server, &config.compress, cache, etagger, access, lookup);
+    let cache = cache.as_ref().unwrap();
+
+    let call = |method: Method| {
+        let mut req = Request::new(method, "/app.js".parse().unwrap());
+        req.headers_mut().set_raw("Accept-Encoding", "br, gzip");
+        let res = service.call(req).wait().unwrap();
+        let encoding = res.headers().get::<header::ContentEncoding>().cloned();
+        (encoding, res.body().concat2().wait().unwrap().to_vec())
+    };
+
+    // A HEAD doesn't fill the cache, a GET does
+    call(Method::Head);
+    assert!(cache.get("/app.js").is_none());
+    assert_eq!(call(Method::Get), (None, b"plain".to_vec()));
+    assert!(cache.get("/app.js").is_some());
+
+    // A sidecar that shows up after the file was cached is served once the entry is rechecked
+    fs::write(root.join("app.js.gz"), "gzipped").unwrap();
+    ::std::thread::sleep(::std::time::Duration::from_millis(1_100));
+    assert_eq!(call(Method::Get), (Some(header::ContentEncoding(vec![header::Encoding::Gzip])), b"gzipped".to_vec()));
+
+    // Hits don't wait on the pool, so they're answered even while it's busy
+    let (unblock, blocked) = ::std::sync::mpsc::channel::<()>();
+    pool.spawn_fn(move || {
+        blocked.recv().ok();
+        Ok::<(), ()>(())
+    }).forget();
+
+    let mut req = Request::new(Method::Get, "/app.js".parse().unwrap());
+    req.headers_mut().set_raw("Accept-Encoding", "gzip");
+    let res = match poll_once(service.call(req)) {
+        Some(res) => res,
+        None => panic!("cache hit waited on the pool"),
+    };
+    assert_eq!(res.headers().get(), Some(&header::ContentEncoding(vec![header::Encoding::Gzip])));
+    assert_eq!(poll_once(res.body().concat2()).unwrap().to_vec(), b"gzipped".to_vec());
+
+    unblock.send(()).unwrap();
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_multiple_ranges() {
+    use std::fs;
+    use futures::Stream;
+    use leak::Leak;
+    use toml;
+    use config::Config;
+
+    let root = ::std::env::temp_dir().join(format!("hunk-test-root-multiple-ranges-{}", ::std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    let root = root.canonicalize().unwrap();
+    let text = "0123456789".repeat(100);
+    fs::write(root.join("big.txt"), &text).unwrap();
+    fs::write(root.join("small.txt"), "0123456789").unwrap();
+
+    let config: &'static Config = Box::new(toml::from_str::<Config>(&format!(r#"
+        [server]
+        root = {:?}
+    "#, root)).unwrap()).leak();
+
+    let pool = Box::new(CpuPool::new(1)).leak();
+    let cache = Box::new(None).leak();
+    let etagger = Box::new(ETagger::new(Default::default())).leak();
+    let access = Box::new(Access::default()).leak();
+    let lookup = Box::new(Lookup::default()).leak();
+    let service = Root::new(pool, &config.server, &config.compress, cache, etagger, access, lookup);
+
+    let call = |uri: &str| {
+        let mut req = Request::new(Method::Get, uri.parse().unwrap());
+        req.headers_mut().set_raw("Range", "bytes=0-1,5-6");
+        let res = service.call(req).wait().unwrap();
+        let status = res.status();
+        let headers = res.headers().clone();
+        (status, headers, String::from_utf8(res.body().concat2().wait().unwrap().to_vec()).unwrap())
+    };
+
+    let (status, headers, body) = call("/big.txt");
+    assert_eq!(status, StatusCode::PartialContent);
+    let content_type = String::from_utf8(headers.get_raw("Content-Type").unwrap().one().unwrap().to_vec()).unwrap();
+    assert!(content_type.starts_with("multipart/byteranges; boundary="));
+    let boundary = &content_type["multipart/byteranges; boundary=".len()..];
+    assert_eq!(body, format!(
+        "--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/1000\r\n\r\n01\r\n\
+         --{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 5-6/1000\r\n\r\n56\r\n\
+         --{b}--\r\n",
+        b = boundary,
+    ));
+    assert_eq!(headers.get(), Some(&header::ContentLength(body.len() as u64)));
+
+    // The framing would outweigh the 10 bytes it saves, so it's the whole file instead
+    let (status, headers, body) = call("/small.txt");
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(body, "0123456789");
+    assert_eq!(headers.get(), Some(&header::ContentLength(10)));
+    assert!(headers.get::<header::ContentRange>().is_none());
+
+    fs::remove_dir_all(&root).unwrap();
+}
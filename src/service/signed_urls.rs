@@ -0,0 +1,58 @@
+// Expiring links for [signed_urls]. Requests under a protected prefix need an
+// ?expires=...&sig=... query string from `hunk sign`, and the peer has to match
+// the ip the link was signed for, if any. Anything else gets a 403.
+
+use std::net::SocketAddr;
+
+use futures::{future::ok, Future};
+use hyper::{self, Request, Response, server::Service};
+
+use config::SignedUrls as Config;
+use path;
+use response;
+use signed_url::{self, Signer};
+
+#[derive(Debug)]
+pub struct SignedUrls<T> {
+    peer: Option<SocketAddr>,
+    config: &'static Option<Config>,
+    signer: &'static Option<Signer>,
+    next: T,
+}
+
+impl<T> SignedUrls<T> {
+    pub fn new(peer: Option<SocketAddr>, config: &'static Option<Config>, signer: &'static Option<Signer>, next: T) -> Self where T: Service + 'static {
+        SignedUrls { peer, config, signer, next }
+    }
+}
+
+impl<T> Service for SignedUrls<T> where T: Service<Request = Request, Response = Response, Error = hyper::Error> + 'static {
+    type Request = T::Request;
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let (config, signer) = match (self.config, self.signer) {
+            (Some(config), Some(signer)) =>
+                (config, signer),
+            _ =>
+                return Box::new(self.next.call(req)),
+        };
+
+        let path = match path::decode_path(req.path()) {
+            Ok(ref path) if config.protects(path) =>
+                path.clone(),
+            _ =>
+                return Box::new(self.next.call(req)),
+        };
+
+        let peer = self.peer.map(|peer| peer.ip());
+        if let Err(e) = signer.verify(&path, req.query(), peer, signed_url::now()) {
+            debug!("rejected signed url for {}: {}", path, e);
+            return Box::new(ok(response::forbidden()));
+        }
+
+        Box::new(self.next.call(req))
+    }
+}
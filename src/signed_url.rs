@@ -0,0 +1,167 @@
+// Expiring links for [signed_urls], e.g. for handing a build artifact to someone
+// outside without putting it behind a login.
+//
+//     /builds/app.zip?expires=1700000000&sig=<hex>
+//     /builds/app.zip?expires=1700000000&ip=203.0.113.7&sig=<hex>
+//
+// sig is an HMAC-SHA256 over the decoded path, the expiry (unix seconds), and the
+// optional client IP, so none of them can be changed without the key.
+
+use std::fs;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use url::form_urlencoded;
+
+use config;
+use util::constant_time_eq;
+
+pub struct Signer {
+    key: Vec<u8>,
+}
+
+impl ::std::fmt::Debug for Signer {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Signer")
+    }
+}
+
+impl Signer {
+    pub fn new(config: &config::SignedUrls) -> Result<Signer, String> {
+        let text = fs::read_to_string(&config.key)
+            .map_err(|e| format!("could not read signing key {:?}: {}", config.key, e))?;
+        Signer::from_key(text.trim().as_bytes())
+    }
+
+    fn from_key(key: &[u8]) -> Result<Signer, String> {
+        if key.len() < 16 {
+            return Err("the signing key should be at least 16 bytes".to_string());
+        }
+        Ok(Signer { key: key.to_vec() })
+    }
+
+    fn signature(&self, path: &str, expires: u64, ip: Option<&str>) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.key).expect("hmac takes keys of any size");
+        mac.input(format!("{}\n{}\n{}", path, expires, ip.unwrap_or("")).as_bytes());
+        format!("{:x}", mac.result().code())
+    }
+
+    // The query string that signs the decoded path until expires (unix seconds).
+    pub fn sign(&self, path: &str, expires: u64, ip: Option<IpAddr>) -> String {
+        let ip = ip.map(|ip| ip.to_string());
+        let sig = self.signature(path, expires, ip.as_deref());
+
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        query.append_pair("expires", &expires.to_string());
+        if let Some(ref ip) = ip {
+            query.append_pair("ip", ip);
+        }
+        query.append_pair("sig", &sig);
+        query.finish()
+    }
+
+    // path is the decoded request path and now is in unix seconds.
+    pub fn verify(&self, path: &str, query: Option<&str>, peer: Option<IpAddr>, now: u64) -> Result<(), String> {
+        let (mut expires, mut ip, mut sig) = (None, None, None);
+        for (key, value) in form_urlencoded::parse(query.unwrap_or("").as_bytes()) {
+            match key.as_ref() {
+                "expires" => expires = Some(value.into_owned()),
+                "ip" => ip = Some(value.into_owned()),
+                "sig" => sig = Some(value.into_owned()),
+                _ => {},
+            }
+        }
+
+        let sig = sig.ok_or("no sig")?;
+        let expires = expires
+            .ok_or("no expires")?
+            .parse::<u64>()
+            .map_err(|_| "invalid expires")?;
+
+        let expected = self.signature(path, expires, ip.as_deref());
+        if !constant_time_eq(expected.as_bytes(), sig.to_lowercase().as_bytes()) {
+            return Err("bad sig".to_string());
+        }
+
+        if now > expires {
+            return Err("expired".to_string());
+        }
+
+        if let Some(ip) = ip {
+            let ip = ip.parse::<IpAddr>().map_err(|_| "invalid ip")?;
+            if peer != Some(ip) {
+                return Err(format!("signed for {}, not {:?}", ip, peer));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Unix seconds, what expires is measured in
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// e.g. "90" or "90s", "15m", "1h", "7d"
+pub fn parse_ttl(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (n, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => (&s[..i], &s[i..]),
+        None => (s, "s"),
+    };
+    let n = n.parse::<u64>().map_err(|_| format!("invalid ttl {:?}", s))?;
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => return Err(format!("invalid ttl {:?}, use s, m, h, or d", s)),
+    };
+    let secs = n.checked_mul(unit).ok_or_else(|| format!("ttl {:?} is too long", s))?;
+    Ok(Duration::from_secs(secs))
+}
+
+#[test]
+fn test_verify() {
+    let signer = Signer::from_key(b"0123456789abcdef").unwrap();
+    let other = Signer::from_key(b"fedcba9876543210").unwrap();
+
+    let peer: IpAddr = "203.0.113.7".parse().unwrap();
+    let stranger: IpAddr = "198.51.100.1".parse().unwrap();
+
+    let query = signer.sign("/builds/app 1.zip", 1_000, None);
+    let bound = signer.sign("/builds/app 1.zip", 1_000, Some(peer));
+
+    assert!(signer.verify("/builds/app 1.zip", Some(&query), None, 999).is_ok());
+    assert!(signer.verify("/builds/app 1.zip", Some(&query), None, 1_000).is_ok());
+    assert!(signer.verify("/builds/app 1.zip", Some(&query), None, 1_001).is_err());
+    assert!(signer.verify("/builds/app 2.zip", Some(&query), None, 999).is_err());
+    assert!(other.verify("/builds/app 1.zip", Some(&query), None, 999).is_err());
+    assert!(signer.verify("/builds/app 1.zip", Some(&query.replace("expires=1000", "expires=9000")), None, 999).is_err());
+    assert!(signer.verify("/builds/app 1.zip", None, None, 999).is_err());
+
+    assert!(signer.verify("/builds/app 1.zip", Some(&bound), Some(peer), 999).is_ok());
+    assert!(signer.verify("/builds/app 1.zip", Some(&bound), Some(stranger), 999).is_err());
+    assert!(signer.verify("/builds/app 1.zip", Some(&bound), None, 999).is_err());
+    // Dropping the ip breaks the sig
+    let unbound = bound.split('&').filter(|pair| !pair.starts_with("ip=")).collect::<Vec<_>>().join("&");
+    assert!(signer.verify("/builds/app 1.zip", Some(&unbound), Some(stranger), 999).is_err());
+
+    assert!(Signer::from_key(b"short").is_err());
+}
+
+#[test]
+fn test_parse_ttl() {
+    assert_eq!(parse_ttl("90"), Ok(Duration::from_secs(90)));
+    assert_eq!(parse_ttl("90s"), Ok(Duration::from_secs(90)));
+    assert_eq!(parse_ttl("15m"), Ok(Duration::from_secs(900)));
+    assert_eq!(parse_ttl("1h"), Ok(Duration::from_secs(3_600)));
+    assert_eq!(parse_ttl("7d"), Ok(Duration::from_secs(604_800)));
+    assert!(parse_ttl("1w").is_err());
+    assert!(parse_ttl("h").is_err());
+    assert!(parse_ttl("").is_err());
+    assert!(parse_ttl("18446744073709551615d").is_err());
+}
@@ -1,15 +1,22 @@
-use std::u64;
 use std::time::Duration;
 
+use futures::{Future, Sink, Stream};
+use futures_cpupool::CpuPool;
+use hyper::{self, Body, Chunk};
 use unicase::Ascii;
 
 pub fn duration_as_millis(d: Duration) -> u64 {
     d.as_secs() * 1_000 + u64::from(d.subsec_nanos()) / 1_000_000
 }
 
+// Compares secrets without leaking how much of them matched through timing.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 // If the Vary header is empty, then create it.
 // If it's Vary::Any, then do nothing. (i.e. will already Vary)
-// If it's Vary::Items, append to the array.
+// If it's Vary::Items, append to the array unless it's already there.
 pub fn append_header_vary(headers: &mut ::hyper::Headers, item: Ascii<String>) {
     use hyper::header::Vary;
 
@@ -17,12 +24,33 @@ pub fn append_header_vary(headers: &mut ::hyper::Headers, item: Ascii<String>) {
         Some(&mut Vary::Any) =>
             {},
         Some(&mut Vary::Items(ref mut xs)) =>
-            xs.push(item),
+            if !xs.contains(&item) {
+                xs.push(item)
+            },
         None =>
             headers.set(Vary::Items(vec![item]))
     }
 }
 
+// Drive the stream on the pool and hand back the receiving end as a Body.
+//
+// Stream errors are forwarded into the body so that hyper aborts the response rather
+// than us panicking on the pool. send_all itself only fails when the client hangs up.
+//
+// TODO: Replace with Body::wrap_stream() when Hyper 0.12.x releases.
+pub fn spawn_body<S>(pool: &CpuPool, stream: S) -> Body
+    where S: Stream<Item = Chunk, Error = hyper::Error> + Send + 'static
+{
+    let (tx, body) = Body::pair();
+
+    let future = tx.send_all(stream.then(Ok))
+        .map(|_| ())
+        .map_err(|_| ());
+
+    pool.spawn(future).forget();
+
+    body
+}
 
 // These macros are used for middleware composition.
 // I added a case to https://crates.io/crates/pipeline.
@@ -30,25 +58,25 @@ pub fn append_header_vary(headers: &mut ::hyper::Headers, item: Ascii<String>) {
 #[macro_export]
 macro_rules! pipe_fun {
     (&, $ret:expr) => {
-        &$ret;
+        &$ret
     };
     ((as $typ:ty), $ret:expr) => {
-        $ret as $typ;
+        $ret as $typ
     };
     ({$fun:expr}, $ret:expr) => {
-        $fun($ret);
+        $fun($ret)
     };
     ([$fun:ident], $ret:expr) => {
-        $ret.$fun();
+        $ret.$fun()
     };
     (($fun:path[$($arg:expr),*]), $ret:expr) => {
-        $fun($($arg,)* $ret);
+        $fun($($arg,)* $ret)
     };
     (($fun:ident($($arg:expr),*)), $ret:expr) => {
-        $fun($ret $(,$arg)*);
+        $fun($ret $(,$arg)*)
     };
     ($fun:ident, $ret:expr) => {
-        $fun($ret);
+        $fun($ret)
     }
 }
 
@@ -0,0 +1,81 @@
+// A small config file that lives next to the content, e.g. _redirects or .hunkignore.
+//
+// It's re-read whenever its mtime changes, checked at most once per RELOAD_INTERVAL_SECS
+// so that we aren't stat()ing it on every request.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+const RELOAD_INTERVAL_SECS: u64 = 1;
+
+// A file that's parsed again whenever it changes on disk. A missing file parses as empty.
+pub struct Watched<T> {
+    path: PathBuf,
+    parse: fn(&str) -> T,
+    state: Mutex<State<T>>,
+}
+
+struct State<T> {
+    checked: Option<Instant>,
+    mtime: Option<SystemTime>,
+    value: Arc<T>,
+}
+
+impl<T: Default> Watched<T> {
+    pub fn new(path: PathBuf, parse: fn(&str) -> T) -> Self {
+        Watched {
+            path,
+            parse,
+            state: Mutex::new(State { checked: None, mtime: None, value: Arc::new(T::default()) }),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // The value as of the last check, without touching the disk. None when it's
+    // due for another check, so that callers on the reactor can get() it from the pool.
+    pub fn peek(&self) -> Option<Arc<T>> {
+        let state = self.state.lock().unwrap();
+        if is_fresh(state.checked) {
+            Some(Arc::clone(&state.value))
+        } else {
+            None
+        }
+    }
+
+    // Touches the filesystem every RELOAD_INTERVAL_SECS, so call it from the pool.
+    pub fn get(&self) -> Arc<T> {
+        let mut state = self.state.lock().unwrap();
+
+        if is_fresh(state.checked) {
+            return Arc::clone(&state.value);
+        }
+        state.checked = Some(Instant::now());
+
+        let mtime = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if mtime != state.mtime {
+            state.value = Arc::new(match fs::read_to_string(&self.path) {
+                Ok(text) => {
+                    info!("loaded {:?}", self.path);
+                    (self.parse)(&text)
+                }
+                Err(_) =>
+                    T::default(),
+            });
+            state.mtime = mtime;
+        }
+
+        Arc::clone(&state.value)
+    }
+}
+
+fn is_fresh(checked: Option<Instant>) -> bool {
+    match checked {
+        Some(checked) => checked.elapsed() < Duration::from_secs(RELOAD_INTERVAL_SECS),
+        None => false,
+    }
+}